@@ -731,6 +731,9 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> PostgresBackend<IO> {
                 self.write_message_noflush(&BeMessage::ReadyForQuery)?;
             }
 
+            // output is flushed after each message anyway
+            FeMessage::Flush => {}
+
             FeMessage::Terminate => {
                 return Ok(ProcessMsgResult::Break);
             }
@@ -836,6 +839,13 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> PostgresBackendReader<IO> {
         }
     }
 
+    /// Refuse messages longer than `max_len` instead of buffering them, so
+    /// the peer can't make us allocate arbitrary amount of memory. Exceeding
+    /// the limit is a protocol error, closing the connection.
+    pub fn set_max_message_len(&mut self, max_len: usize) {
+        self.reader.set_max_message_len(max_len);
+    }
+
     /// Get CopyData contents of the next message in COPY stream or error
     /// closing it. The error type is wider than actual errors which can happen
     /// here -- it includes 'Other' and 'ServerInitiated', but that's ok for
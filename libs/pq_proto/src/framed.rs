@@ -123,6 +123,7 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
         let reader = FramedReader {
             stream: read_half,
             read_buf: self.read_buf,
+            max_message_len: usize::MAX,
         };
         let writer = FramedWriter {
             stream: write_half,
@@ -145,11 +146,22 @@ impl<S: AsyncRead + AsyncWrite + Unpin> Framed<S> {
 pub struct FramedReader<S> {
     stream: ReadHalf<S>,
     read_buf: BytesMut,
+    max_message_len: usize,
 }
 
 impl<S: AsyncRead + Unpin> FramedReader<S> {
     pub async fn read_message(&mut self) -> Result<Option<FeMessage>, ConnectionError> {
-        read_message(&mut self.stream, &mut self.read_buf, FeMessage::parse).await
+        let max_len = self.max_message_len;
+        read_message(&mut self.stream, &mut self.read_buf, |buf| {
+            FeMessage::parse_limited(buf, max_len)
+        })
+        .await
+    }
+
+    /// Fail reading messages longer than `max_len`, see
+    /// [`FeMessage::parse_limited`].
+    pub fn set_max_message_len(&mut self, max_len: usize) {
+        self.max_message_len = max_len;
     }
 }
 
@@ -14,6 +14,7 @@ pub use postgres_protocol::PG_EPOCH;
 pub type Oid = u32;
 pub type SystemId = u64;
 
+pub const BYTEA_OID: Oid = 17;
 pub const INT8_OID: Oid = 20;
 pub const INT4_OID: Oid = 23;
 pub const TEXT_OID: Oid = 25;
@@ -29,6 +30,7 @@ pub enum FeMessage {
     Execute(FeExecuteMessage),
     Close(FeCloseMessage),
     Sync,
+    Flush,
     Terminate,
     CopyData(Bytes),
     CopyDone,
@@ -215,6 +217,16 @@ impl FeMessage {
     //
     // Inspired by rust-postgres Message::parse.
     pub fn parse(buf: &mut BytesMut) -> Result<Option<FeMessage>, ProtocolError> {
+        Self::parse_limited(buf, usize::MAX)
+    }
+
+    /// Like [`FeMessage::parse`], but fails if the message is longer than
+    /// `max_len` (counting length field, but not message type), before
+    /// reserving space for it in `buf`.
+    pub fn parse_limited(
+        buf: &mut BytesMut,
+        max_len: usize,
+    ) -> Result<Option<FeMessage>, ProtocolError> {
         // Every message contains message type byte and 4 bytes len; can't do
         // much without them.
         if buf.len() < 5 {
@@ -233,6 +245,12 @@ impl FeMessage {
                 len
             )));
         }
+        if len as usize > max_len {
+            return Err(ProtocolError::Protocol(format!(
+                "message length {} exceeds limit {}",
+                len, max_len
+            )));
+        }
 
         // length field includes itself, but not message type.
         let total_len = len as usize + 1;
@@ -255,6 +273,7 @@ impl FeMessage {
             b'B' => Ok(Some(FeBindMessage::parse(msg)?)),
             b'C' => Ok(Some(FeCloseMessage::parse(msg)?)),
             b'S' => Ok(Some(FeMessage::Sync)),
+            b'H' => Ok(Some(FeMessage::Flush)),
             b'X' => Ok(Some(FeMessage::Terminate)),
             b'd' => Ok(Some(FeMessage::CopyData(msg))),
             b'c' => Ok(Some(FeMessage::CopyDone)),
@@ -964,6 +983,24 @@ mod tests {
         let params = make_params("foo\\ bar \\ \\\\ baz\\  lol");
         assert_eq!(split_options(&params), ["foo bar", " \\", "baz ", "lol"]);
     }
+
+    #[test]
+    fn test_parse_limited() {
+        let mut buf = BytesMut::new();
+        buf.put_u8(b'd');
+        buf.put_u32(4 + 3);
+        buf.put_slice(b"abc");
+
+        // full message isn't needed to refuse it
+        let mut header = BytesMut::from(&buf[..5]);
+        assert!(FeMessage::parse_limited(&mut header, 6).is_err());
+        assert!(header.capacity() < 7);
+
+        match FeMessage::parse_limited(&mut buf, 7).unwrap() {
+            Some(FeMessage::CopyData(data)) => assert_eq!(&data[..], b"abc"),
+            msg => panic!("unexpected message {msg:?}"),
+        }
+    }
 }
 
 fn terminate_code(code: &[u8; 5]) -> [u8; 6] {
@@ -59,6 +59,8 @@ pub mod defaults {
     pub const DEFAULT_CONCURRENT_TENANT_SIZE_LOGICAL_SIZE_QUERIES: usize =
         super::ConfigurableSemaphore::DEFAULT_INITIAL.get();
 
+    pub const DEFAULT_CONCURRENT_LAYER_DOWNLOADS: usize = 64;
+
     pub const DEFAULT_METRIC_COLLECTION_INTERVAL: &str = "10 min";
     pub const DEFAULT_CACHED_METRIC_COLLECTION_INTERVAL: &str = "1 hour";
     pub const DEFAULT_METRIC_COLLECTION_ENDPOINT: Option<reqwest::Url> = None;
@@ -88,6 +90,8 @@ pub mod defaults {
 
 #concurrent_tenant_size_logical_size_queries = '{DEFAULT_CONCURRENT_TENANT_SIZE_LOGICAL_SIZE_QUERIES}'
 
+#concurrent_layer_downloads = {DEFAULT_CONCURRENT_LAYER_DOWNLOADS}
+
 #metric_collection_interval = '{DEFAULT_METRIC_COLLECTION_INTERVAL}'
 #cached_metric_collection_interval = '{DEFAULT_CACHED_METRIC_COLLECTION_INTERVAL}'
 #synthetic_size_calculation_interval = '{DEFAULT_SYNTHETIC_SIZE_CALCULATION_INTERVAL}'
@@ -178,6 +182,14 @@ pub struct PageServerConf {
     /// See the comment in `eviction_task` for details.
     pub eviction_task_immitated_concurrent_logical_size_queries: ConfigurableSemaphore,
 
+    /// Number of concurrent on-demand layer downloads, shared by GetPage
+    /// requests and background tasks. Further downloads queue for a permit.
+    ///
+    /// Defaults to [`defaults::DEFAULT_CONCURRENT_LAYER_DOWNLOADS`]. Downloads
+    /// were not limited before this setting was added, set it higher to get
+    /// closer to that.
+    pub concurrent_layer_downloads: ConfigurableSemaphore,
+
     // How often to collect metrics and send them to the metrics endpoint.
     pub metric_collection_interval: Duration,
     // How often to send unchanged cached metrics to the metrics endpoint.
@@ -261,6 +273,8 @@ struct PageServerConfigBuilder {
 
     concurrent_tenant_size_logical_size_queries: BuilderValue<NonZeroUsize>,
 
+    concurrent_layer_downloads: BuilderValue<NonZeroUsize>,
+
     metric_collection_interval: BuilderValue<Duration>,
     cached_metric_collection_interval: BuilderValue<Duration>,
     metric_collection_endpoint: BuilderValue<Option<Url>>,
@@ -311,6 +325,8 @@ impl Default for PageServerConfigBuilder {
             concurrent_tenant_size_logical_size_queries: Set(
                 ConfigurableSemaphore::DEFAULT_INITIAL,
             ),
+            concurrent_layer_downloads: Set(NonZeroUsize::new(DEFAULT_CONCURRENT_LAYER_DOWNLOADS)
+                .expect("default concurrent layer downloads is non zero")),
             metric_collection_interval: Set(humantime::parse_duration(
                 DEFAULT_METRIC_COLLECTION_INTERVAL,
             )
@@ -419,6 +435,10 @@ impl PageServerConfigBuilder {
         self.concurrent_tenant_size_logical_size_queries = BuilderValue::Set(u);
     }
 
+    pub fn concurrent_layer_downloads(&mut self, u: NonZeroUsize) {
+        self.concurrent_layer_downloads = BuilderValue::Set(u);
+    }
+
     pub fn metric_collection_interval(&mut self, metric_collection_interval: Duration) {
         self.metric_collection_interval = BuilderValue::Set(metric_collection_interval)
     }
@@ -522,6 +542,10 @@ impl PageServerConfigBuilder {
             eviction_task_immitated_concurrent_logical_size_queries: ConfigurableSemaphore::new(
                 concurrent_tenant_size_logical_size_queries,
             ),
+            concurrent_layer_downloads: ConfigurableSemaphore::new(
+                self.concurrent_layer_downloads
+                    .ok_or(anyhow!("missing concurrent_layer_downloads"))?,
+            ),
             metric_collection_interval: self
                 .metric_collection_interval
                 .ok_or(anyhow!("missing metric_collection_interval"))?,
@@ -719,6 +743,9 @@ impl PageServerConf {
                     let permits = input.parse::<usize>().context("expected a number of initial permits, not {s:?}")?;
                     NonZeroUsize::new(permits).context("initial semaphore permits out of range: 0, use other configuration to disable a feature")?
                 }),
+                "concurrent_layer_downloads" => builder.concurrent_layer_downloads(
+                    NonZeroUsize::new(parse_toml_u64(key, item)? as usize).context("concurrent_layer_downloads must be positive")?
+                ),
                 "metric_collection_interval" => builder.metric_collection_interval(parse_toml_duration(key, item)?),
                 "cached_metric_collection_interval" => builder.cached_metric_collection_interval(parse_toml_duration(key, item)?),
                 "metric_collection_endpoint" => {
@@ -897,6 +924,9 @@ impl PageServerConf {
             concurrent_tenant_size_logical_size_queries: ConfigurableSemaphore::default(),
             eviction_task_immitated_concurrent_logical_size_queries: ConfigurableSemaphore::default(
             ),
+            concurrent_layer_downloads: ConfigurableSemaphore::new(
+                NonZeroUsize::new(defaults::DEFAULT_CONCURRENT_LAYER_DOWNLOADS).unwrap(),
+            ),
             metric_collection_interval: Duration::from_secs(60),
             cached_metric_collection_interval: Duration::from_secs(60 * 60),
             metric_collection_endpoint: defaults::DEFAULT_METRIC_COLLECTION_ENDPOINT,
@@ -1110,6 +1140,9 @@ background_task_maximum_delay = '334 s'
                 concurrent_tenant_size_logical_size_queries: ConfigurableSemaphore::default(),
                 eviction_task_immitated_concurrent_logical_size_queries:
                     ConfigurableSemaphore::default(),
+                concurrent_layer_downloads: ConfigurableSemaphore::new(
+                    NonZeroUsize::new(defaults::DEFAULT_CONCURRENT_LAYER_DOWNLOADS).unwrap()
+                ),
                 metric_collection_interval: humantime::parse_duration(
                     defaults::DEFAULT_METRIC_COLLECTION_INTERVAL
                 )?,
@@ -1173,6 +1206,9 @@ background_task_maximum_delay = '334 s'
                 concurrent_tenant_size_logical_size_queries: ConfigurableSemaphore::default(),
                 eviction_task_immitated_concurrent_logical_size_queries:
                     ConfigurableSemaphore::default(),
+                concurrent_layer_downloads: ConfigurableSemaphore::new(
+                    NonZeroUsize::new(defaults::DEFAULT_CONCURRENT_LAYER_DOWNLOADS).unwrap()
+                ),
                 metric_collection_interval: Duration::from_secs(222),
                 cached_metric_collection_interval: Duration::from_secs(22200),
                 metric_collection_endpoint: Some(Url::parse("http://localhost:80/metrics")?),
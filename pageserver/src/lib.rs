@@ -12,6 +12,7 @@ pub mod page_cache;
 pub mod page_service;
 pub mod pgdatadir_mapping;
 pub mod repository;
+pub mod shutdown_hooks;
 pub(crate) mod statvfs;
 pub mod task_mgr;
 pub mod tenant;
@@ -65,6 +66,9 @@ pub async fn shutdown_pageserver(exit_code: i32) {
 
     // There should be nothing left, but let's be sure
     task_mgr::shutdown_tasks(None, None, None).await;
+
+    // Cleanup registered by embedders.
+    shutdown_hooks::run_shutdown_hooks(shutdown_hooks::SHUTDOWN_HOOK_TIMEOUT).await;
     info!("Shut down successfully completed");
     std::process::exit(exit_code);
 }
@@ -0,0 +1,85 @@
+//! Cleanup hooks run by [`crate::shutdown_pageserver`].
+//!
+//! Code embedding the pageserver can register its own cleanup (flushing
+//! metrics, closing connections to co-located processes, etc.) without
+//! changing the fixed shutdown sequence. Hooks run after all pageserver tasks
+//! are shut down, right before the process exits.
+
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use once_cell::sync::Lazy;
+use tracing::{info, warn};
+
+/// How long a single hook may run before shutdown proceeds without it.
+pub const SHUTDOWN_HOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub type ShutdownHook = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+
+struct RegisteredHook {
+    name: String,
+    priority: i32,
+    hook: ShutdownHook,
+}
+
+static SHUTDOWN_HOOKS: Lazy<Mutex<Vec<RegisteredHook>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register a hook to run on shutdown. Hooks run one by one in ascending
+/// priority order, hooks with equal priority in registration order.
+pub fn register_shutdown_hook(name: impl Into<String>, priority: i32, hook: ShutdownHook) {
+    SHUTDOWN_HOOKS.lock().unwrap().push(RegisteredHook {
+        name: name.into(),
+        priority,
+        hook,
+    });
+}
+
+/// Run and unregister all registered hooks, giving each one at most
+/// `timeout` to complete.
+pub async fn run_shutdown_hooks(timeout: Duration) {
+    let mut hooks = std::mem::take(&mut *SHUTDOWN_HOOKS.lock().unwrap());
+    // stable, so registration order is kept within a priority
+    hooks.sort_by_key(|h| h.priority);
+    for RegisteredHook { name, hook, .. } in hooks {
+        info!("running shutdown hook {name}");
+        if tokio::time::timeout(timeout, hook()).await.is_err() {
+            warn!("shutdown hook {name} didn't complete in {timeout:?}, skipping it");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_shutdown_hooks() {
+        let ran = Arc::new(Mutex::new(Vec::new()));
+        for (name, priority) in [("late", 10), ("early", -10), ("stuck", 0), ("middle", 0)] {
+            let ran = Arc::clone(&ran);
+            register_shutdown_hook(
+                name,
+                priority,
+                Box::new(move || {
+                    async move {
+                        if name == "stuck" {
+                            futures::future::pending::<()>().await;
+                        }
+                        ran.lock().unwrap().push(name);
+                    }
+                    .boxed()
+                }),
+            );
+        }
+
+        run_shutdown_hooks(Duration::from_millis(10)).await;
+        assert_eq!(*ran.lock().unwrap(), ["early", "middle", "late"]);
+
+        // Hooks run only once.
+        run_shutdown_hooks(Duration::from_millis(10)).await;
+        assert_eq!(ran.lock().unwrap().len(), 3);
+    }
+}
@@ -88,6 +88,8 @@ use crate::shutdown_pageserver;
 // storage, it will start to download it. If a background operation needs a remote layer,
 // and the download was already initiated by a GetPage request, the background task
 // will wait for the download - running in the Page server runtime - to finish.
+// The number of downloads in progress is bounded by `concurrent_layer_downloads`;
+// GetPage requests and background operations queue for the same download permits.
 // Another example: the initial tenant loading tasks are launched in the background ops
 // runtime. If a GetPage request comes in before the load of a tenant has finished, the
 // GetPage request will wait for the tenant load to finish.
@@ -4386,4 +4386,76 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_download_waits_for_permit() -> anyhow::Result<()> {
+        use remote_storage::{RemoteStorageConfig, RemoteStorageKind};
+
+        let harness = TenantHarness::create("test_download_waits_for_permit")?;
+        let remote_fs_dir = harness.conf.workdir.join("remote_fs");
+        std::fs::create_dir_all(&remote_fs_dir)?;
+        let storage = GenericRemoteStorage::from_config(&RemoteStorageConfig {
+            max_concurrent_syncs: std::num::NonZeroUsize::new(
+                remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS,
+            )
+            .unwrap(),
+            max_sync_errors: std::num::NonZeroU32::new(
+                remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS,
+            )
+            .unwrap(),
+            storage: RemoteStorageKind::LocalFs(std::fs::canonicalize(remote_fs_dir)?),
+        })?;
+
+        let ctx = RequestContext::new(TaskKind::UnitTest, DownloadBehavior::Error);
+        let tenant = Tenant::new(
+            TenantState::Active,
+            harness.conf,
+            TenantConfOpt::from(harness.tenant_conf),
+            Arc::new(TestRedoManager),
+            harness.tenant_id,
+            Some(storage),
+        );
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(0x10), DEFAULT_PG_VERSION, &ctx)
+            .await?;
+
+        let layer_name = {
+            let guard = tline.layers.read().await;
+            let (layers, _) = &*guard;
+            let layer = layers.iter_historic_layers().next().unwrap();
+            layer.filename().file_name()
+        };
+        assert_eq!(tline.evict_layer(&layer_name).await?, Some(true));
+
+        // Take all download permits, as if that many downloads were running
+        let limit = harness.conf.concurrent_layer_downloads.inner();
+        let mut permits = Vec::new();
+        while let Ok(permit) = Arc::clone(limit).try_acquire_owned() {
+            permits.push(permit);
+        }
+        assert!(!permits.is_empty());
+
+        let download = tline.download_layer(&layer_name);
+        tokio::pin!(download);
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), &mut download)
+                .await
+                .is_err(),
+            "download should wait for a permit"
+        );
+
+        // Releasing one lets the download go through, and it gives the
+        // permit back when it is done
+        permits.pop();
+        let downloaded = tokio::time::timeout(Duration::from_secs(10), &mut download)
+            .await
+            .expect("download should get the released permit")?;
+        assert_eq!(downloaded, Some(true));
+        assert_eq!(limit.available_permits(), 1);
+
+        // The layer is local again
+        assert_eq!(tline.download_layer(&layer_name).await?, Some(false));
+
+        Ok(())
+    }
 }
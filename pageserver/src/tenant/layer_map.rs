@@ -318,6 +318,14 @@ impl LayerMap {
         Ok(true)
     }
 
+    /// Drop historic versions of the layer map that are only needed for
+    /// searches below `horizon`. Searches at `horizon` or above, including
+    /// the ones that page reconstruction continues with below it, are
+    /// unaffected.
+    pub fn prune_history(&mut self, horizon: Lsn) {
+        self.historic.prune(horizon.0);
+    }
+
     pub fn iter_historic_layers(&self) -> impl '_ + Iterator<Item = Arc<PersistentLayerDesc>> {
         self.historic.iter()
     }
@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::Range;
 
 use tracing::info;
@@ -82,6 +82,36 @@ impl<Value: Clone> HistoricLayerCoverage<Value> {
         }
     }
 
+    /// Drop historic versions that are not needed to answer queries at
+    /// `horizon` or above, or at any of the `keep` LSNs below it. For each of
+    /// these the version visible at that LSN is kept, so their results don't
+    /// change.
+    ///
+    /// Returns the LSN of the version visible at the horizon. Versions below
+    /// it may be missing, so the state below it can't be recovered by trimming.
+    ///
+    /// Versions share structure, so this only frees the tree nodes that are
+    /// not referenced by any of the retained versions.
+    pub fn prune(&mut self, horizon: u64, keep: impl IntoIterator<Item = u64>) -> u64 {
+        let keep_from = match self.historic.range(..=horizon).next_back() {
+            Some((lsn, _)) => *lsn,
+            None => return 0, // nothing below the horizon
+        };
+        let keep: BTreeSet<u64> = keep
+            .into_iter()
+            .filter(|lsn| *lsn < keep_from)
+            .filter_map(|lsn| self.historic.range(..=lsn).next_back().map(|(v, _)| *v))
+            .collect();
+        self.historic
+            .retain(|lsn, _| *lsn >= keep_from || keep.contains(lsn));
+        keep_from
+    }
+
+    /// Number of retained historic versions.
+    pub fn num_versions(&self) -> usize {
+        self.historic.len()
+    }
+
     /// Add a layer
     ///
     /// Panics if new layer has older lsn.start than an existing layer.
@@ -116,6 +146,65 @@ impl<Value: Clone> HistoricLayerCoverage<Value> {
         }
     }
 
+    /// Find the layers needed to reconstruct the page at `key` as of
+    /// `end_lsn` (exclusive): the latest image layer below end_lsn, and the
+    /// delta layers between it and end_lsn. This follows the same rules as
+    /// LayerMap::search, repeated until an image layer is reached.
+    ///
+    /// `lsn_range` returns the LSN range of a layer value.
+    ///
+    /// Complexity: O(D log N), where D is the number of delta layers returned.
+    pub fn reconstruction_set(
+        &self,
+        key: i128,
+        end_lsn: u64,
+        lsn_range: impl Fn(&Value) -> Range<u64>,
+    ) -> ReconstructionSet<Value> {
+        let mut deltas = Vec::new();
+        let mut end_lsn = end_lsn;
+        while end_lsn > 0 {
+            let version = match self.get_version(end_lsn - 1) {
+                Some(version) => version,
+                None => break,
+            };
+            let latest_delta = version.delta_coverage.query(key);
+            let latest_image = version.image_coverage.query(key);
+
+            match (latest_delta, latest_image) {
+                (None, None) => break,
+                (None, Some(image)) => {
+                    return ReconstructionSet {
+                        image: Some(image),
+                        deltas,
+                    }
+                }
+                (Some(delta), None) => {
+                    end_lsn = lsn_range(&delta).start;
+                    deltas.push(delta);
+                }
+                (Some(delta), Some(image)) => {
+                    let delta_lsn = lsn_range(&delta);
+                    let img_lsn = lsn_range(&image);
+                    let image_is_newer = img_lsn.end >= delta_lsn.end;
+                    let image_exact_match = img_lsn.start + 1 == end_lsn;
+                    if image_is_newer || image_exact_match {
+                        return ReconstructionSet {
+                            image: Some(image),
+                            deltas,
+                        };
+                    }
+                    end_lsn = std::cmp::max(delta_lsn.start, img_lsn.start + 1);
+                    deltas.push(delta);
+                }
+            }
+        }
+
+        ReconstructionSet {
+            image: None,
+            deltas,
+        }
+    }
+
     /// Remove all entries after a certain LSN (inclusive)
     pub fn trim(&mut self, begin: &u64) {
         self.historic.split_off(begin);
@@ -129,6 +218,17 @@ impl<Value: Clone> HistoricLayerCoverage<Value> {
     }
 }
 
+/// Layers needed to reconstruct a page, see
+/// HistoricLayerCoverage::reconstruction_set.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ReconstructionSet<Value> {
+    /// Image layer to start from. None if there is no image of the page
+    /// below the oldest returned delta layer.
+    pub image: Option<Value>,
+    /// Delta layers to apply on top of the image, newest first.
+    pub deltas: Vec<Value>,
+}
+
 /// This is the most basic test that demonstrates intended usage.
 /// All layers in this test have height 1.
 #[test]
@@ -392,6 +492,110 @@ fn test_persistent_overlapping() {
     assert_eq!(version.image_coverage.query(8), Some("Layer 6".to_string()));
 }
 
+/// Check that pruning frees the values only referenced by dropped versions,
+/// and that queries at the retained LSNs are unaffected.
+#[test]
+fn test_prune_versions() {
+    use std::sync::{Arc, Weak};
+
+    // Each layer covers the same keys as the previous one, so the previous
+    // value is only referenced by the older versions.
+    let mut map = HistoricLayerCoverage::<Arc<String>>::new();
+    let mut values = Vec::new();
+    for i in 0..100 {
+        let value = Arc::new(format!("Layer {i}"));
+        values.push(Arc::downgrade(&value));
+        map.insert(
+            LayerKey {
+                key: 0..10,
+                lsn: (i as u64 * 10)..(i as u64 * 10 + 1),
+                is_image: true,
+            },
+            value,
+        );
+    }
+    // A second key range that is not overwritten
+    map.insert(
+        LayerKey {
+            key: 20..30,
+            lsn: 1000..1001,
+            is_image: true,
+        },
+        Arc::new("Layer 100".to_string()),
+    );
+    assert_eq!(map.num_versions(), 101);
+    let alive = |values: &[Weak<String>]| values.iter().filter(|v| v.strong_count() > 0).count();
+    assert_eq!(alive(&values), 100);
+
+    // Prune between two versions, keeping the one visible at 305 too
+    assert_eq!(map.prune(905, [305]), 900);
+    assert_eq!(map.num_versions(), 12);
+    assert_eq!(
+        map.historic.keys().copied().take(3).collect::<Vec<_>>(),
+        [300, 900, 910]
+    );
+
+    // Only the values of the retained versions are still referenced
+    assert_eq!(alive(&values), 11);
+    assert!(values[30].upgrade().is_some());
+    assert!(values[29].upgrade().is_none());
+    assert!(values[31].upgrade().is_none());
+
+    assert!(map.get_version(299).is_none());
+    let version = map.get_version(305).unwrap();
+    assert_eq!(*version.image_coverage.query(0).unwrap(), "Layer 30");
+    let version = map.get_version(899).unwrap();
+    assert_eq!(*version.image_coverage.query(0).unwrap(), "Layer 30");
+    let version = map.get_version(905).unwrap();
+    assert_eq!(*version.image_coverage.query(0).unwrap(), "Layer 90");
+    let version = map.get_version(1000).unwrap();
+    assert_eq!(*version.image_coverage.query(0).unwrap(), "Layer 99");
+    assert_eq!(*version.image_coverage.query(20).unwrap(), "Layer 100");
+}
+
+#[test]
+fn test_reconstruction_set() {
+    let mut map = HistoricLayerCoverage::<LayerKey>::new();
+    let layers = [
+        (0..10, 10..11, true),
+        (0..10, 11..20, false),
+        (0..10, 20..30, false),
+        (0..10, 30..31, true),
+        (0..10, 31..40, false),
+        (0..100, 40..50, false),
+    ];
+    for (key, lsn, is_image) in layers {
+        let layer = LayerKey { key, lsn, is_image };
+        map.insert(layer.clone(), layer);
+    }
+    let query = |key, end_lsn| {
+        let set = map.reconstruction_set(key, end_lsn, |layer| layer.lsn.clone());
+        (
+            set.image.map(|layer| layer.lsn.start),
+            set.deltas
+                .iter()
+                .map(|layer| layer.lsn.start)
+                .collect::<Vec<_>>(),
+        )
+    };
+
+    // Delta layers come newest first, down to the image
+    assert_eq!(query(5, 45), (Some(30), vec![40, 31]));
+    assert_eq!(query(5, 25), (Some(10), vec![20, 11]));
+    assert_eq!(query(5, 15), (Some(10), vec![11]));
+
+    // Image exactly at the requested LSN needs no deltas
+    assert_eq!(query(5, 31), (Some(30), vec![]));
+    assert_eq!(query(5, 11), (Some(10), vec![]));
+
+    // No image below the deltas
+    assert_eq!(query(50, 45), (None, vec![40]));
+
+    // Nothing at all
+    assert_eq!(query(5, 10), (None, vec![]));
+    assert_eq!(query(200, 45), (None, vec![]));
+}
+
 /// Wrapper for HistoricLayerCoverage that allows us to hack around the lack
 /// of support for retroactive insertion by rebuilding the map since the
 /// change.
@@ -422,6 +626,12 @@ pub struct BufferedHistoricLayerCoverage<Value> {
 
     /// All current layers. This is not used for search. Only to make rebuilds easier.
     layers: BTreeMap<LayerKey, Value>,
+
+    /// Horizon of the last prune, reapplied when rebuilding from scratch.
+    prune_horizon: Option<u64>,
+
+    /// Versions below this LSN might have been pruned.
+    pruned_below: u64,
 }
 
 impl<T: std::fmt::Debug> std::fmt::Debug for BufferedHistoricLayerCoverage<T> {
@@ -445,7 +655,42 @@ impl<Value: Clone> BufferedHistoricLayerCoverage<Value> {
             historic_coverage: HistoricLayerCoverage::<Value>::new(),
             buffer: BTreeMap::new(),
             layers: BTreeMap::new(),
+            prune_horizon: None,
+            pruned_below: 0,
+        }
+    }
+
+    /// Drop historic versions that are not needed to answer queries at
+    /// `horizon` or above, see HistoricLayerCoverage::prune. The horizon is
+    /// remembered and applied again after rebuilds that restore the dropped
+    /// versions. It is not expected to decrease.
+    ///
+    /// Page reconstruction starting at or above the horizon can still
+    /// continue below it, at the bottom of the delta layers and at the image
+    /// layers it finds. The versions visible there are kept.
+    pub fn prune(&mut self, horizon: u64) {
+        if !self.buffer.is_empty() {
+            panic!("rebuild pls")
         }
+
+        self.prune_horizon = Some(horizon);
+        self.apply_prune();
+    }
+
+    fn apply_prune(&mut self) {
+        let horizon = match self.prune_horizon {
+            Some(horizon) => horizon,
+            None => return,
+        };
+        // Same LSNs as the next LayerMap::search call after finding the layer
+        let keep = self.layers.keys().filter_map(|layer_key| {
+            if layer_key.is_image {
+                Some(layer_key.lsn.start)
+            } else {
+                layer_key.lsn.start.checked_sub(1)
+            }
+        });
+        self.pruned_below = self.historic_coverage.prune(horizon, keep);
     }
 
     pub fn insert(&mut self, layer_key: LayerKey, value: Value) {
@@ -458,10 +703,15 @@ impl<Value: Clone> BufferedHistoricLayerCoverage<Value> {
 
     pub fn rebuild(&mut self) {
         // Find the first LSN that needs to be rebuilt
-        let rebuild_since: u64 = match self.buffer.iter().next() {
+        let mut rebuild_since: u64 = match self.buffer.iter().next() {
             Some((LayerKey { lsn, .. }, _)) => lsn.start,
             None => return, // No need to rebuild if buffer is empty
         };
+        // Trimming restores head from the last version below rebuild_since,
+        // which might have been pruned, so rebuild everything then.
+        if rebuild_since <= self.pruned_below {
+            rebuild_since = 0;
+        }
 
         // Apply buffered updates to self.layers
         let num_updates = self.buffer.len();
@@ -491,6 +741,9 @@ impl<Value: Clone> BufferedHistoricLayerCoverage<Value> {
                 .insert(layer_key.clone(), layer.clone());
             num_inserted += 1;
         }
+        if rebuild_since == 0 {
+            self.apply_prune();
+        }
 
         // TODO maybe only warn if ratio is at least 10
         info!(
@@ -524,6 +777,98 @@ impl<Value: Clone> BufferedHistoricLayerCoverage<Value> {
     }
 }
 
+/// Check that page reconstruction at or above the prune horizon is
+/// unaffected by pruning, also after retroactive updates.
+#[test]
+fn test_retroactive_prune() {
+    let lsn_range = |layer: &String| {
+        let lsn = layer.split(' ').nth(1).unwrap().parse::<u64>().unwrap();
+        lsn..(lsn + 10)
+    };
+    let mut map = BufferedHistoricLayerCoverage::new();
+    // An old image of keys 0..10, and deltas on top of that for keys 0..5.
+    // Reconstruction continues right below each delta, so the versions at
+    // the delta starts can be pruned.
+    map.insert(
+        LayerKey {
+            key: 0..10,
+            lsn: 10..20,
+            is_image: true,
+        },
+        "Image 10".to_string(),
+    );
+    for i in 3..10 {
+        map.insert(
+            LayerKey {
+                key: 0..5,
+                lsn: (i * 10)..(i * 10 + 10),
+                is_image: false,
+            },
+            format!("Delta {}", i * 10),
+        );
+    }
+    // Images of keys 20..30 in between
+    for i in 2..10 {
+        map.insert(
+            LayerKey {
+                key: 20..30,
+                lsn: (i * 10 + 5)..(i * 10 + 6),
+                is_image: true,
+            },
+            format!("Image {}", i * 10 + 5),
+        );
+    }
+    map.rebuild();
+
+    let check = |map: &BufferedHistoricLayerCoverage<String>| {
+        let historic = map.get().unwrap();
+        let set = historic.reconstruction_set(0, 91, lsn_range);
+        assert_eq!(set.image, Some("Image 10".to_string()));
+        assert_eq!(set.deltas.len(), 7);
+        let set = historic.reconstruction_set(7, 91, lsn_range);
+        assert_eq!(set.image, Some("Image 10".to_string()));
+        assert!(set.deltas.is_empty());
+        let set = historic.reconstruction_set(25, 91, lsn_range);
+        assert_eq!(set.image, Some("Image 85".to_string()));
+    };
+
+    check(&map);
+    let before = map.get().unwrap().num_versions();
+    map.prune(90);
+    let after = map.get().unwrap().num_versions();
+    assert!(after < before, "{after} < {before}");
+    check(&map);
+
+    // Retroactive insert below the horizon, everything is rebuilt and
+    // pruned again.
+    map.insert(
+        LayerKey {
+            key: 20..30,
+            lsn: 17..18,
+            is_image: true,
+        },
+        "Image 17".to_string(),
+    );
+    map.rebuild();
+    assert_eq!(map.get().unwrap().num_versions(), after + 1);
+    check(&map);
+
+    // Insert above the horizon trims back to a retained version
+    map.insert(
+        LayerKey {
+            key: 0..5,
+            lsn: 100..110,
+            is_image: true,
+        },
+        "Image 100".to_string(),
+    );
+    map.rebuild();
+    assert_eq!(map.get().unwrap().num_versions(), after + 2);
+    check(&map);
+    let set = map.get().unwrap().reconstruction_set(0, 101, lsn_range);
+    assert_eq!(set.image, Some("Image 100".to_string()));
+}
+
 #[test]
 fn test_retroactive_regression_1() {
     let mut map = BufferedHistoricLayerCoverage::new();
@@ -546,6 +891,56 @@ fn test_retroactive_regression_1() {
     );
 }
 
+#[test]
+fn test_retroactive_fingerprint() {
+    use super::layer_coverage::fingerprint_divergence;
+
+    let layers: Vec<_> = (0..50u64)
+        .map(|i| {
+            let key_start = ((i * 37) % 100) as i128;
+            let layer_key = LayerKey {
+                key: key_start..key_start + 1 + (i % 7) as i128,
+                lsn: i * 10..i * 10 + 5 + (i % 3) * 10,
+                is_image: i % 4 == 0,
+            };
+            (layer_key, format!("Layer {i}"))
+        })
+        .collect();
+
+    // Insert in LSN order directly
+    let mut sequential = HistoricLayerCoverage::new();
+    for (layer_key, value) in &layers {
+        sequential.insert(layer_key.clone(), value.clone());
+    }
+
+    // Insert out of order, forcing retroactive rebuilds
+    let mut buffered = BufferedHistoricLayerCoverage::new();
+    for (layer_key, value) in layers.iter().rev().step_by(2) {
+        buffered.insert(layer_key.clone(), value.clone());
+    }
+    buffered.rebuild();
+    for (layer_key, value) in layers.iter().rev().skip(1).step_by(2) {
+        buffered.insert(layer_key.clone(), value.clone());
+        buffered.rebuild();
+    }
+    let buffered = buffered.get().unwrap();
+
+    for lsn in [0, 5, 100, 255, 1000] {
+        let a = sequential.get_version(lsn).unwrap();
+        let b = buffered.get_version(lsn).unwrap();
+        let divergence = fingerprint_divergence(
+            &a.image_coverage.fingerprint(),
+            &b.image_coverage.fingerprint(),
+        );
+        assert_eq!(divergence, None, "image coverage at {lsn}");
+        let divergence = fingerprint_divergence(
+            &a.delta_coverage.fingerprint(),
+            &b.delta_coverage.fingerprint(),
+        );
+        assert_eq!(divergence, None, "delta coverage at {lsn}");
+    }
+}
+
 #[test]
 fn test_retroactive_simple() {
     let mut map = BufferedHistoricLayerCoverage::new();
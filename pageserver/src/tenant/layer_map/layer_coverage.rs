@@ -61,10 +61,11 @@ impl<Value: Clone> LayerCoverage<Value> {
         self.nodes.insert_mut(key, value);
     }
 
-    /// Insert a layer.
+    /// Insert a layer. Returns false if the layer is fully occluded by
+    /// layers with higher lsn.end, so no query result changed.
     ///
     /// Complexity: worst case O(N), in practice O(log N). See NOTE in implementation.
-    pub fn insert(&mut self, key: Range<i128>, lsn: Range<u64>, value: Value) {
+    pub fn insert(&mut self, key: Range<i128>, lsn: Range<u64>, value: Value) -> bool {
         // Add nodes at endpoints
         //
         // NOTE The order of lines is important. We add nodes at the start
@@ -97,6 +98,10 @@ impl<Value: Clone> LayerCoverage<Value> {
             }
             prev_covered = needs_cover;
         }
+        // Nodes added at the endpoints don't change coverage by themselves,
+        // and every node to remove follows one to update.
+        let changed = !to_update.is_empty();
+
         // TODO check if the nodes inserted at key.start and key.end are safe
         //      to remove. It's fine to keep them but they could be redundant.
         for k in to_update {
@@ -105,6 +110,7 @@ impl<Value: Clone> LayerCoverage<Value> {
         for k in to_remove {
             self.nodes.remove_mut(&k);
         }
+        changed
     }
 
     /// Get the latest (by lsn.end) layer at a given key
@@ -130,12 +136,78 @@ impl<Value: Clone> LayerCoverage<Value> {
             .map(|(k, v)| (*k, v.as_ref().map(|x| x.1.clone())))
     }
 
+    /// Like self.range, but returns at most max changes along with the key to
+    /// continue from: passing it as the start of the next range resumes the
+    /// iteration. The continuation is None if the range is exhausted.
+    ///
+    /// Complexity: O(log N + max)
+    pub fn range_limited(
+        &self,
+        key: Range<i128>,
+        max: usize,
+    ) -> (Vec<(i128, Option<Value>)>, Option<i128>) {
+        let mut iter = self.range(key);
+        let changes = iter.by_ref().take(max).collect();
+        let continuation = iter.next().map(|(k, _)| k);
+        (changes, continuation)
+    }
+
+    /// Total width of covered key ranges, gaps excluded.
+    ///
+    /// Each covered node spans until the next node. The last node is always
+    /// the uncovered end of the key range of some layer, so there is no
+    /// unbounded covered segment.
+    ///
+    /// Complexity: O(N)
+    pub fn covered_width(&self) -> i128 {
+        let mut width = 0;
+        let mut covered_since = None;
+        for (k, node) in self.nodes.iter() {
+            if let Some(start) = covered_since.take() {
+                width += k - start;
+            }
+            if node.is_some() {
+                covered_since = Some(*k);
+            }
+        }
+        debug_assert!(covered_since.is_none(), "last node must be uncovered");
+        width
+    }
+
     /// O(1) clone
     pub fn clone(&self) -> Self {
         Self {
             nodes: self.nodes.clone(),
         }
     }
+
+    /// All nodes with their (lsn.end, value), in key order. Unlike query
+    /// results, this exposes the exact structure, so comparing fingerprints
+    /// catches changes in how coverage is built.
+    ///
+    /// Complexity: O(N)
+    #[cfg(any(test, feature = "testing"))]
+    pub fn fingerprint(&self) -> Vec<(i128, Option<(u64, Value)>)> {
+        self.nodes.iter().map(|(k, v)| (*k, v.clone())).collect()
+    }
+}
+
+/// Describe the first difference between two coverage fingerprints, if any.
+#[cfg(any(test, feature = "testing"))]
+pub fn fingerprint_divergence<Value: PartialEq + std::fmt::Debug>(
+    a: &[(i128, Option<(u64, Value)>)],
+    b: &[(i128, Option<(u64, Value)>)],
+) -> Option<String> {
+    for (i, (node_a, node_b)) in a.iter().zip(b.iter()).enumerate() {
+        if node_a != node_b {
+            return Some(format!("node {i} differs: {node_a:?} vs {node_b:?}"));
+        }
+    }
+    match a.len().cmp(&b.len()) {
+        std::cmp::Ordering::Equal => None,
+        std::cmp::Ordering::Less => Some(format!("extra node {:?} in second", b[a.len()])),
+        std::cmp::Ordering::Greater => Some(format!("extra node {:?} in first", a[b.len()])),
+    }
 }
 
 /// Image and delta coverage at a specific LSN.
@@ -161,3 +233,68 @@ impl<Value: Clone> LayerCoverageTuple<Value> {
         }
     }
 }
+
+#[test]
+fn test_insert_reports_change() {
+    let mut map = LayerCoverage::<String>::new();
+    assert!(map.insert(0..100, 0..50, "Layer 1".to_string()));
+
+    // Fully below existing coverage
+    assert!(!map.insert(10..20, 10..30, "Layer 2".to_string()));
+    assert_eq!(map.query(15), Some("Layer 1".to_string()));
+
+    // Partially above existing coverage
+    assert!(map.insert(90..110, 40..60, "Layer 3".to_string()));
+    assert_eq!(map.query(95), Some("Layer 3".to_string()));
+
+    // Covers a gap only
+    assert!(map.insert(200..210, 0..1, "Layer 4".to_string()));
+    assert!(!map.insert(200..210, 0..1, "Layer 4".to_string()));
+}
+
+#[test]
+fn test_covered_width() {
+    let mut map = LayerCoverage::<String>::new();
+    assert_eq!(map.covered_width(), 0);
+
+    map.insert(0..10, 0..10, "Layer 1".to_string());
+    map.insert(20..30, 0..10, "Layer 2".to_string());
+    // Overlaps Layer 2 and extends past it, splitting it in coverage
+    map.insert(25..40, 10..20, "Layer 3".to_string());
+    // Gap in 10..20 and 40..100
+    map.insert(100..105, 0..10, "Layer 4".to_string());
+    assert_eq!(map.covered_width(), 10 + 20 + 5);
+
+    // Fully occluded layer doesn't change anything
+    map.insert(26..30, 0..5, "Layer 5".to_string());
+    assert_eq!(map.covered_width(), 35);
+
+    // Filling the gap
+    map.insert(5..25, 0..1, "Layer 6".to_string());
+    assert_eq!(map.covered_width(), 45);
+}
+
+#[test]
+fn test_range_limited() {
+    let mut map = LayerCoverage::<String>::new();
+    for i in 0..100 {
+        map.insert(i * 10..i * 10 + 5, 0..1, format!("Layer {i}"));
+    }
+    let full: Vec<_> = map.range(0..1000).collect();
+    assert_eq!(full.len(), 200);
+
+    for max in [1, 7, 200, 1000] {
+        let mut paginated = Vec::new();
+        let mut start = 0;
+        loop {
+            let (changes, continuation) = map.range_limited(start..1000, max);
+            assert!(changes.len() <= max);
+            paginated.extend(changes);
+            match continuation {
+                Some(k) => start = k,
+                None => break,
+            }
+        }
+        assert_eq!(paginated, full);
+    }
+}
@@ -19,7 +19,7 @@ use pageserver_api::models::{
 use remote_storage::GenericRemoteStorage;
 use serde_with::serde_as;
 use storage_broker::BrokerClientChannel;
-use tokio::sync::{oneshot, watch, TryAcquireError};
+use tokio::sync::{oneshot, watch, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio_util::sync::CancellationToken;
 use tracing::*;
 use utils::id::TenantTimelineId;
@@ -4307,6 +4307,14 @@ impl Timeline {
         }
         updates.flush();
 
+        // Reads below the new cutoff are only allowed at the branch points.
+        // The next GC checks for image layers right below its cutoff.
+        let prune_horizon = retain_lsns
+            .iter()
+            .copied()
+            .fold(Lsn(new_gc_cutoff.0 - 1), std::cmp::min);
+        layers.prune_history(prune_horizon);
+
         info!(
             "GC completed removing {} layers, cutoff {}",
             result.layers_removed, new_gc_cutoff
@@ -4448,6 +4456,11 @@ impl Timeline {
             }
         };
 
+        // Only the first waiter of this layer gets here, the others are queued
+        // on `ongoing_download` above and don't need a download permit.
+        let download_permit =
+            acquire_download_permit(self.conf.concurrent_layer_downloads.inner()).await;
+
         let (sender, receiver) = tokio::sync::oneshot::channel();
         // Spawn a task so that download does not outlive timeline when we detach tenant / delete timeline.
         let self_clone = self.myself.upgrade().expect("timeline is gone");
@@ -4538,6 +4551,7 @@ impl Timeline {
                 // XXX: This resets the exponential backoff because it's a new call to
                 // download_layer file.
                 drop(permit);
+                drop(download_permit);
 
                 Ok(())
             }
@@ -4884,3 +4898,13 @@ pub fn compare_arced_layers<L: ?Sized>(left: &Arc<L>, right: &Arc<L>) -> bool {
 
     left == right
 }
+
+/// Wait for a permit to start a layer download, bounding the number of
+/// downloads in progress to `concurrent_layer_downloads`. Semaphore is fair,
+/// so GetPage requests and background tasks are served in arrival order.
+async fn acquire_download_permit(limit: &Arc<Semaphore>) -> OwnedSemaphorePermit {
+    Arc::clone(limit)
+        .acquire_owned()
+        .await
+        .expect("download limit semaphore is never closed")
+}
@@ -26,9 +26,12 @@ use utils::pid_file;
 use metrics::set_build_info_metric;
 use safekeeper::defaults::{
     DEFAULT_HEARTBEAT_TIMEOUT, DEFAULT_HTTP_LISTEN_ADDR, DEFAULT_MAX_OFFLOADER_LAG_BYTES,
-    DEFAULT_PG_LISTEN_ADDR,
+    DEFAULT_PG_LISTEN_ADDR, DEFAULT_REPLICA_FEEDBACK_TIMEOUT, DEFAULT_WALPROPOSER_MAX_MESSAGE_SIZE,
+    DEFAULT_WALPROPOSER_REPLY_COMBINE_TIMEOUT,
 };
+use safekeeper::receive_wal;
 use safekeeper::wal_service;
+use safekeeper::wal_storage::WalSyncMethod;
 use safekeeper::GlobalTimelines;
 use safekeeper::SafeKeeperConf;
 use safekeeper::{broker, WAL_SERVICE_RUNTIME};
@@ -48,6 +51,8 @@ use utils::{
 
 const PID_FILE_NAME: &str = "safekeeper.pid";
 const ID_FILE_NAME: &str = "safekeeper.id";
+/// How long to wait for WAL acceptors to flush received WAL on shutdown.
+const WAL_ACCEPTORS_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
 
 project_git_version!(GIT_VERSION);
 
@@ -81,6 +86,10 @@ struct Args {
     /// Do not wait for changes to be written safely to disk. Unsafe.
     #[arg(short, long)]
     no_sync: bool,
+    /// How to make WAL durable on flush: fdatasync, fsync or open_dsync
+    /// (open segments with O_DSYNC). Ignored with --no-sync.
+    #[arg(long, default_value = "fdatasync")]
+    wal_sync_method: WalSyncMethod,
     /// Dump control file at path specified by this argument and exit.
     #[arg(long)]
     dump_control_file: Option<PathBuf>,
@@ -124,6 +133,56 @@ struct Args {
     /// useful for debugging.
     #[arg(long)]
     current_thread_runtime: bool,
+    /// Combine replies to walproposer into a single socket flush until this
+    /// many bytes are buffered. 0 flushes each reply separately.
+    #[arg(long, default_value = "0")]
+    walproposer_reply_combine_bytes: usize,
+    /// Flush combined replies to walproposer if no new reply arrives during
+    /// this period passed as a human readable duration.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_WALPROPOSER_REPLY_COMBINE_TIMEOUT)]
+    walproposer_reply_combine_timeout: Duration,
+    /// If walproposer doesn't read replies fast enough, keep only the latest
+    /// AppendResponse instead of blocking WAL processing until there is room
+    /// for all of them.
+    #[arg(long)]
+    walproposer_coalesce_replies: bool,
+    /// Flush WAL and reply to walproposer after processing at most this many
+    /// AppendRequests in a row, so that replies are not postponed under
+    /// sustained load. 0 means no limit.
+    #[arg(long, default_value = "0")]
+    walproposer_max_append_batch: usize,
+    /// Close connection of walproposer sending a message larger than this
+    /// many bytes, rather than buffering it.
+    #[arg(long, default_value_t = DEFAULT_WALPROPOSER_MAX_MESSAGE_SIZE)]
+    walproposer_max_message_size: usize,
+    /// Drop WAL read by walsenders from page cache, so streaming doesn't
+    /// evict hot data. The segment currently being written is kept cached.
+    /// Pages are dropped by whichever walsender reads them
+    /// first, so with several receivers per timeline (pageserver and
+    /// standbys) the lagging ones read WAL from disk instead of cache.
+    #[arg(long)]
+    wal_reader_drop_cache: bool,
+    /// Terminate replication connections which didn't stream any WAL during
+    /// this period passed as a human readable duration, so the receiver
+    /// reconnects afresh. Disabled if not set.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    walsender_idle_timeout: Option<Duration>,
+    /// Pause streaming WAL to a replica once it is this many bytes ahead of
+    /// the apply position the replica reported (remote_consistent_lsn for
+    /// pageserver). No limit by default.
+    #[arg(long)]
+    walsender_max_apply_lag_bytes: Option<u64>,
+    /// Skip Sync and Flush messages which replicas send in the middle of the
+    /// COPY stream instead of terminating replication. This only concerns
+    /// protocol message tags; other unexpected ones still terminate it.
+    #[arg(long)]
+    walsender_lenient_replies: bool,
+    /// Without WAL backup, keep WAL not yet received by connected replicas
+    /// only while they report their position at least once during this period
+    /// passed as a human readable duration, so a stuck replica can't block WAL
+    /// removal forever. With WAL backup, replicas don't hold WAL removal.
+    #[arg(long, value_parser = humantime::parse_duration, default_value = DEFAULT_REPLICA_FEEDBACK_TIMEOUT)]
+    replica_feedback_timeout: Duration,
 }
 
 #[tokio::main(flavor = "current_thread")]
@@ -182,6 +241,7 @@ async fn main() -> anyhow::Result<()> {
         listen_http_addr: args.listen_http,
         availability_zone: args.availability_zone,
         no_sync: args.no_sync,
+        wal_sync_method: args.wal_sync_method,
         broker_endpoint: args.broker_endpoint,
         broker_keepalive_interval: args.broker_keepalive_interval,
         heartbeat_timeout: args.heartbeat_timeout,
@@ -191,6 +251,16 @@ async fn main() -> anyhow::Result<()> {
         backup_parallel_jobs: args.wal_backup_parallel_jobs,
         auth,
         current_thread_runtime: args.current_thread_runtime,
+        walproposer_reply_combine_bytes: args.walproposer_reply_combine_bytes,
+        walproposer_reply_combine_timeout: args.walproposer_reply_combine_timeout,
+        walproposer_coalesce_replies: args.walproposer_coalesce_replies,
+        walproposer_max_append_batch: args.walproposer_max_append_batch,
+        walproposer_max_message_size: args.walproposer_max_message_size,
+        wal_reader_drop_cache: args.wal_reader_drop_cache,
+        walsender_idle_timeout: args.walsender_idle_timeout,
+        walsender_max_apply_lag_bytes: args.walsender_max_apply_lag_bytes,
+        walsender_lenient_replies: args.walsender_lenient_replies,
+        replica_feedback_timeout: args.replica_feedback_timeout,
     };
 
     // initialize sentry if SENTRY_DSN is provided
@@ -317,6 +387,8 @@ async fn start_safekeeper(conf: SafeKeeperConf) -> Result<()> {
         _ = sigterm_stream.recv() => info!("received SIGTERM, terminating")
 
     };
+    // Don't lose WAL received but not flushed yet.
+    receive_wal::shutdown_wal_acceptors(WAL_ACCEPTORS_SHUTDOWN_TIMEOUT).await;
     std::process::exit(0);
 }
 
@@ -16,7 +16,7 @@ use crate::{GlobalTimelines, SafeKeeperConf};
 use postgres_backend::QueryError;
 use postgres_backend::{self, PostgresBackend};
 use postgres_ffi::PG_TLI;
-use pq_proto::{BeMessage, FeStartupPacket, RowDescriptor, INT4_OID, TEXT_OID};
+use pq_proto::{BeMessage, FeStartupPacket, RowDescriptor, BYTEA_OID, INT4_OID, TEXT_OID};
 use regex::Regex;
 use utils::auth::{Claims, Scope};
 use utils::{
@@ -43,6 +43,7 @@ enum SafekeeperPostgresCommand {
     StartWalPush,
     StartReplication { start_lsn: Lsn },
     IdentifySystem,
+    TimelineHistory { tli: u32 },
     JSONCtrl { cmd: AppendLogicalMessage },
 }
 
@@ -62,6 +63,12 @@ fn parse_cmd(cmd: &str) -> anyhow::Result<SafekeeperPostgresCommand> {
         Ok(SafekeeperPostgresCommand::StartReplication { start_lsn })
     } else if cmd.starts_with("IDENTIFY_SYSTEM") {
         Ok(SafekeeperPostgresCommand::IdentifySystem)
+    } else if let Some(tli) = cmd.strip_prefix("TIMELINE_HISTORY") {
+        let tli = tli
+            .trim()
+            .parse()
+            .context("parse timeline from TIMELINE_HISTORY command")?;
+        Ok(SafekeeperPostgresCommand::TimelineHistory { tli })
     } else if cmd.starts_with("JSON_CTRL") {
         let cmd = cmd.strip_prefix("JSON_CTRL").context("invalid prefix")?;
         Ok(SafekeeperPostgresCommand::JSONCtrl {
@@ -77,6 +84,7 @@ fn cmd_to_string(cmd: &SafekeeperPostgresCommand) -> &str {
         SafekeeperPostgresCommand::StartWalPush => "START_WAL_PUSH",
         SafekeeperPostgresCommand::StartReplication { .. } => "START_REPLICATION",
         SafekeeperPostgresCommand::IdentifySystem => "IDENTIFY_SYSTEM",
+        SafekeeperPostgresCommand::TimelineHistory { .. } => "TIMELINE_HISTORY",
         SafekeeperPostgresCommand::JSONCtrl { .. } => "JSON_CTRL",
     }
 }
@@ -207,6 +215,9 @@ impl<IO: AsyncRead + AsyncWrite + Unpin + Send> postgres_backend::Handler<IO>
                     .await
             }
             SafekeeperPostgresCommand::IdentifySystem => self.handle_identify_system(pgb).await,
+            SafekeeperPostgresCommand::TimelineHistory { tli } => {
+                self.handle_timeline_history(pgb, tli).await
+            }
             SafekeeperPostgresCommand::JSONCtrl { ref cmd } => {
                 handle_json_ctrl(self, pgb, cmd).await
             }
@@ -305,6 +316,50 @@ impl SafekeeperPostgresHandler {
         Ok(())
     }
 
+    ///
+    /// Handle TIMELINE_HISTORY replication command
+    ///
+    async fn handle_timeline_history<IO: AsyncRead + AsyncWrite + Unpin>(
+        &mut self,
+        pgb: &mut PostgresBackend<IO>,
+        tli: u32,
+    ) -> Result<(), QueryError> {
+        // Safekeeper WAL is always on PG_TLI, which has no parent timelines,
+        // so its history is empty. Postgres has no history file for timeline
+        // 1 and errors instead, but clients only ask for history to follow a
+        // timeline switch, and an empty file tells them the same: there is
+        // nothing to follow. Check that the timeline exists anyway to give a
+        // sensible error.
+        GlobalTimelines::get(self.ttid).map_err(|e| QueryError::Other(e.into()))?;
+        if tli != PG_TLI {
+            return Err(QueryError::Other(anyhow::anyhow!(
+                "timeline {tli} doesn't exist, only {PG_TLI} is available"
+            )));
+        }
+
+        let filename = format!("{:08X}.history", tli);
+        pgb.write_message_noflush(&BeMessage::RowDescription(&[
+            RowDescriptor {
+                name: b"filename",
+                typoid: TEXT_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+            RowDescriptor {
+                name: b"content",
+                typoid: BYTEA_OID,
+                typlen: -1,
+                ..Default::default()
+            },
+        ]))?
+        .write_message_noflush(&BeMessage::DataRow(&[
+            Some(filename.as_bytes()),
+            Some(&b""[..]),
+        ]))?
+        .write_message_noflush(&BeMessage::CommandComplete(b"TIMELINE_HISTORY"))?;
+        Ok(())
+    }
+
     /// Returns true if current connection is a replication connection, originating
     /// from a walproposer recovery function. This connection gets a special handling:
     /// safekeeper must stream all local WAL till the flush_lsn, whether committed or not.
@@ -312,3 +367,125 @@ impl SafekeeperPostgresHandler {
         self.appname == Some("wal_proposer_recovery".to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::{create_timeline_with_wal, mock_connection, MockPeer};
+    use bytes::{Buf, Bytes};
+    use postgres_backend::Handler;
+
+    // Run query on the timeline and return field names from RowDescription
+    // and values from the single DataRow of the response.
+    async fn query_row(
+        handler: &mut SafekeeperPostgresHandler,
+        query: &str,
+    ) -> (Vec<String>, Vec<Option<Bytes>>) {
+        let (mut pgb, mut peer) = mock_connection();
+        handler.process_query(&mut pgb, query).await.unwrap();
+        pgb.flush().await.unwrap();
+        let fields = parse_row_description(&mut peer).await;
+        let values = parse_data_row(&mut peer).await;
+        let (tag, body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'C');
+        assert!(body.starts_with(query.split(' ').next().unwrap().as_bytes()));
+        (fields, values)
+    }
+
+    async fn parse_row_description(peer: &mut MockPeer) -> Vec<String> {
+        let (tag, mut body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'T');
+        let mut fields = Vec::new();
+        for _ in 0..body.get_i16() {
+            let name_len = body.iter().position(|&b| b == 0).unwrap();
+            fields.push(String::from_utf8(body.split_to(name_len).to_vec()).unwrap());
+            // terminator, table oid, attnum, type oid, typlen, typmod, format
+            body.advance(1 + 4 + 2 + 4 + 2 + 4 + 2);
+        }
+        assert!(body.is_empty());
+        fields
+    }
+
+    async fn parse_data_row(peer: &mut MockPeer) -> Vec<Option<Bytes>> {
+        let (tag, mut body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'D');
+        let mut values = Vec::new();
+        for _ in 0..body.get_i16() {
+            let len = body.get_i32();
+            values.push((len >= 0).then(|| body.split_to(len as usize)));
+        }
+        assert!(body.is_empty());
+        values
+    }
+
+    async fn timeline_handler(wal: &[u8]) -> (SafekeeperPostgresHandler, Lsn) {
+        let (conf, tli, start_pos) = create_timeline_with_wal(wal).await;
+        let mut handler = SafekeeperPostgresHandler::new(conf, 1, None);
+        handler.tenant_id = Some(tli.ttid.tenant_id);
+        handler.timeline_id = Some(tli.ttid.timeline_id);
+        (handler, start_pos + wal.len() as u64)
+    }
+
+    #[tokio::test]
+    async fn test_identify_system() {
+        let (mut handler, flush_lsn) = timeline_handler(&[0; 100]).await;
+        let (fields, values) = query_row(&mut handler, "IDENTIFY_SYSTEM").await;
+        assert_eq!(fields, ["systemid", "timeline", "xlogpos", "dbname"]);
+        // nothing is committed yet
+        assert_eq!(
+            values,
+            [
+                Some(Bytes::from("0")),
+                Some(Bytes::from(PG_TLI.to_string())),
+                Some(Bytes::from("0/0")),
+                None
+            ]
+        );
+
+        // walproposer recovery gets all local WAL
+        handler.appname = Some("wal_proposer_recovery".to_string());
+        let (_, values) = query_row(&mut handler, "IDENTIFY_SYSTEM").await;
+        assert_eq!(values[2], Some(Bytes::from(flush_lsn.to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_timeline_history() {
+        let (mut handler, _) = timeline_handler(&[]).await;
+        let (fields, values) = query_row(&mut handler, "TIMELINE_HISTORY 1").await;
+        assert_eq!(fields, ["filename", "content"]);
+        assert_eq!(
+            values,
+            [Some(Bytes::from("00000001.history")), Some(Bytes::new())]
+        );
+
+        let (mut pgb, _peer) = mock_connection();
+        let err = handler
+            .process_query(&mut pgb, "TIMELINE_HISTORY 2")
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("timeline 2 doesn't exist"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_parse_replication_cmds() {
+        assert!(matches!(
+            parse_cmd("IDENTIFY_SYSTEM").unwrap(),
+            SafekeeperPostgresCommand::IdentifySystem
+        ));
+        assert!(matches!(
+            parse_cmd("TIMELINE_HISTORY 1").unwrap(),
+            SafekeeperPostgresCommand::TimelineHistory { tli: 1 }
+        ));
+        assert!(parse_cmd("TIMELINE_HISTORY").is_err());
+        assert!(parse_cmd("TIMELINE_HISTORY abc").is_err());
+        match parse_cmd("START_REPLICATION SLOT s PHYSICAL 0/16B3748").unwrap() {
+            SafekeeperPostgresCommand::StartReplication { start_lsn } => {
+                assert_eq!(start_lsn, Lsn(0x16B3748))
+            }
+            _ => panic!("expected START_REPLICATION"),
+        }
+    }
+}
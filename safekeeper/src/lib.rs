@@ -8,6 +8,8 @@ use storage_broker::Uri;
 
 use utils::id::{NodeId, TenantId, TenantTimelineId};
 
+use crate::wal_storage::WalSyncMethod;
+
 mod auth;
 pub mod broker;
 pub mod control_file;
@@ -26,7 +28,10 @@ pub mod timeline;
 pub mod wal_backup;
 pub mod wal_service;
 pub mod wal_storage;
+pub mod wal_transport;
 
+#[cfg(test)]
+mod test_utils;
 mod timelines_global_map;
 use std::sync::Arc;
 pub use timelines_global_map::GlobalTimelines;
@@ -40,6 +45,9 @@ pub mod defaults {
 
     pub const DEFAULT_HEARTBEAT_TIMEOUT: &str = "5000ms";
     pub const DEFAULT_MAX_OFFLOADER_LAG_BYTES: u64 = 128 * (1 << 20);
+    pub const DEFAULT_WALPROPOSER_REPLY_COMBINE_TIMEOUT: &str = "0ms";
+    pub const DEFAULT_WALPROPOSER_MAX_MESSAGE_SIZE: usize = 16 * (1 << 20);
+    pub const DEFAULT_REPLICA_FEEDBACK_TIMEOUT: &str = "10m";
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +64,7 @@ pub struct SafeKeeperConf {
     pub listen_http_addr: String,
     pub availability_zone: Option<String>,
     pub no_sync: bool,
+    pub wal_sync_method: WalSyncMethod,
     pub broker_endpoint: Uri,
     pub broker_keepalive_interval: Duration,
     pub heartbeat_timeout: Duration,
@@ -65,6 +74,16 @@ pub struct SafeKeeperConf {
     pub wal_backup_enabled: bool,
     pub auth: Option<Arc<JwtAuth>>,
     pub current_thread_runtime: bool,
+    pub walproposer_reply_combine_bytes: usize,
+    pub walproposer_reply_combine_timeout: Duration,
+    pub walproposer_coalesce_replies: bool,
+    pub walproposer_max_append_batch: usize,
+    pub walproposer_max_message_size: usize,
+    pub wal_reader_drop_cache: bool,
+    pub walsender_idle_timeout: Option<Duration>,
+    pub walsender_max_apply_lag_bytes: Option<u64>,
+    pub walsender_lenient_replies: bool,
+    pub replica_feedback_timeout: Duration,
 }
 
 impl SafeKeeperConf {
@@ -84,6 +103,7 @@ impl SafeKeeperConf {
         SafeKeeperConf {
             workdir: PathBuf::from("./"),
             no_sync: false,
+            wal_sync_method: WalSyncMethod::Fdatasync,
             listen_pg_addr: defaults::DEFAULT_PG_LISTEN_ADDR.to_string(),
             listen_http_addr: defaults::DEFAULT_HTTP_LISTEN_ADDR.to_string(),
             availability_zone: None,
@@ -99,6 +119,16 @@ impl SafeKeeperConf {
             heartbeat_timeout: Duration::new(5, 0),
             max_offloader_lag_bytes: defaults::DEFAULT_MAX_OFFLOADER_LAG_BYTES,
             current_thread_runtime: false,
+            walproposer_reply_combine_bytes: 0,
+            walproposer_reply_combine_timeout: Duration::ZERO,
+            walproposer_coalesce_replies: false,
+            walproposer_max_append_batch: 0,
+            walproposer_max_message_size: defaults::DEFAULT_WALPROPOSER_MAX_MESSAGE_SIZE,
+            wal_reader_drop_cache: false,
+            walsender_idle_timeout: None,
+            walsender_max_apply_lag_bytes: None,
+            walsender_lenient_replies: false,
+            replica_feedback_timeout: Duration::from_secs(600),
         }
     }
 }
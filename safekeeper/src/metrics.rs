@@ -126,6 +126,20 @@ pub static BACKUP_ERRORS: Lazy<IntCounter> = Lazy::new(|| {
     )
     .expect("Failed to register safekeeper_backup_errors_total counter")
 });
+pub static WAL_SENDER_SENDS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_walsender_sends_total",
+        "Number of WAL chunks sent by walsenders"
+    )
+    .expect("Failed to register safekeeper_walsender_sends_total counter")
+});
+pub static WAL_SENDER_CAPPED_SENDS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "safekeeper_walsender_capped_sends_total",
+        "Number of WAL chunks sent by walsenders which were capped by the send size rather than by available WAL"
+    )
+    .expect("Failed to register safekeeper_walsender_capped_sends_total counter")
+});
 pub static BROKER_PUSH_ALL_UPDATES_SECONDS: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!(
         "safekeeper_broker_push_update_seconds",
@@ -291,6 +305,11 @@ impl WalStorageMetrics {
         self.flush_wal_seconds += seconds;
         FLUSH_WAL_SECONDS.observe(seconds);
     }
+
+    /// Total time spent syncing WAL to disk.
+    pub fn flush_wal_seconds(&self) -> f64 {
+        self.flush_wal_seconds
+    }
 }
 
 /// Accepts async function that returns empty anyhow result, and returns the duration of its execution.
@@ -11,10 +11,13 @@ use crate::wal_service::ConnectionId;
 use crate::GlobalTimelines;
 use anyhow::{anyhow, Context};
 use bytes::BytesMut;
+use futures::pin_mut;
+use once_cell::sync::Lazy;
 use postgres_backend::CopyStreamHandlerEnd;
 use postgres_backend::PostgresBackend;
 use postgres_backend::PostgresBackendReader;
 use postgres_backend::QueryError;
+use pq_proto::framed::ConnectionError;
 use pq_proto::BeMessage;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -22,8 +25,10 @@ use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 use tokio::sync::mpsc::channel;
 use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::Receiver;
 use tokio::sync::mpsc::Sender;
+use tokio::sync::watch;
 use tokio::task;
 use tokio::task::JoinHandle;
 use tokio::time::Duration;
@@ -66,10 +71,12 @@ impl SafekeeperPostgresHandler {
         let (msg_tx, msg_rx) = channel(MSG_QUEUE_SIZE);
         let (reply_tx, reply_rx) = channel(REPLY_QUEUE_SIZE);
         let mut acceptor_handle: Option<JoinHandle<anyhow::Result<()>>> = None;
+        let mut shutdown_rx: Option<watch::Receiver<bool>> = None;
 
         // Concurrently receive and send data; replies are not synchronized with
         // sends, so this avoids deadlocks.
         let mut pgb_reader = pgb.split().context("START_WAL_PUSH split")?;
+        pgb_reader.set_max_message_len(self.conf.walproposer_max_message_size);
         let peer_addr = *pgb.get_peer_addr();
         let network_reader = NetworkReader {
             ttid: self.ttid,
@@ -77,12 +84,32 @@ impl SafekeeperPostgresHandler {
             pgb_reader: &mut pgb_reader,
             peer_addr,
             acceptor_handle: &mut acceptor_handle,
+            shutdown_rx: &mut shutdown_rx,
+            coalesce_replies: self.conf.walproposer_coalesce_replies,
+            max_append_batch: self.conf.walproposer_max_append_batch,
         };
-        let res = tokio::select! {
-            // todo: add read|write .context to these errors
-            r = network_reader.run(msg_tx, msg_rx, reply_tx) => r,
-            r = network_write(pgb, reply_rx) => r,
+        let combining = ReplyCombining {
+            max_bytes: self.conf.walproposer_reply_combine_bytes,
+            timeout: self.conf.walproposer_reply_combine_timeout,
         };
+        let res = {
+            let network_write = network_write(pgb, reply_rx, combining);
+            pin_mut!(network_write);
+            let (read_done, r) = tokio::select! {
+                // todo: add read|write .context to these errors
+                r = network_reader.run(msg_tx, msg_rx, reply_tx) => (true, r),
+                r = &mut network_write => (false, r),
+            };
+            if read_done && r.is_ok() {
+                // WalAcceptor terminated; deliver its last replies, e.g. the
+                // acknowledgement of WAL flushed on shutdown
+                network_write.await
+            } else {
+                r
+            }
+        };
+        // Replies are written (or can't be), let graceful shutdown proceed.
+        drop(shutdown_rx);
 
         // Join pg backend back.
         pgb.unsplit(pgb_reader)?;
@@ -122,6 +149,11 @@ struct NetworkReader<'a, IO> {
     // WalAcceptor is spawned when we learn server info from walproposer and
     // create timeline; handle is put here.
     acceptor_handle: &'a mut Option<JoinHandle<anyhow::Result<()>>>,
+    // Graceful shutdown waits until this is dropped, which happens once
+    // replies of the WalAcceptor are written to the socket.
+    shutdown_rx: &'a mut Option<watch::Receiver<bool>>,
+    coalesce_replies: bool,
+    max_append_batch: usize,
 }
 
 impl<'a, IO: AsyncRead + AsyncWrite + Unpin> NetworkReader<'a, IO> {
@@ -153,10 +185,14 @@ impl<'a, IO: AsyncRead + AsyncWrite + Unpin> NetworkReader<'a, IO> {
             }
         };
 
+        let shutdown_rx = WAL_ACCEPTORS_SHUTDOWN.subscribe();
+        *self.shutdown_rx = Some(shutdown_rx.clone());
         *self.acceptor_handle = Some(WalAcceptor::spawn(
             tli.clone(),
             msg_rx,
-            reply_tx,
+            ReplySender::new(reply_tx, self.coalesce_replies),
+            self.max_append_batch,
+            shutdown_rx,
             self.conn_id,
         ));
 
@@ -188,23 +224,91 @@ async fn read_network_loop<IO: AsyncRead + AsyncWrite + Unpin>(
     }
 }
 
+/// Controls combining of replies to walproposer into a single socket flush.
+#[derive(Debug, Clone, Copy)]
+struct ReplyCombining {
+    /// Flush once this many bytes of replies are buffered; 0 disables
+    /// combining, flushing each reply separately.
+    max_bytes: usize,
+    /// Flush if no new reply arrives within this time, so combining doesn't
+    /// delay replies when the WalAcceptor goes idle.
+    timeout: Duration,
+}
+
+/// Serialized replies waiting to be written to the socket.
+#[derive(Default)]
+struct ReplyBatch {
+    buf: BytesMut,
+    // end offsets of individual replies in buf
+    ends: Vec<usize>,
+}
+
+impl ReplyBatch {
+    fn clear(&mut self) {
+        self.buf.clear();
+        self.ends.clear();
+    }
+
+    fn push(&mut self, msg: &AcceptorProposerMessage) -> anyhow::Result<()> {
+        msg.serialize(&mut self.buf)?;
+        self.ends.push(self.buf.len());
+        Ok(())
+    }
+
+    fn messages(&self) -> impl Iterator<Item = &[u8]> {
+        let starts = std::iter::once(0).chain(self.ends.iter().copied());
+        starts
+            .zip(self.ends.iter().copied())
+            .map(|(start, end)| &self.buf[start..end])
+    }
+}
+
+/// Wait for the next reply and put it into the batch, along with the
+/// following replies if combining is enabled. Returns false if reply_rx is
+/// closed; replies already put into the batch still need to be sent.
+async fn recv_reply_batch(
+    reply_rx: &mut Receiver<AcceptorProposerMessage>,
+    combining: &ReplyCombining,
+    batch: &mut ReplyBatch,
+) -> anyhow::Result<bool> {
+    batch.clear();
+    match reply_rx.recv().await {
+        Some(msg) => batch.push(&msg)?,
+        None => return Ok(false),
+    }
+    let deadline = Instant::now() + combining.timeout;
+    while batch.buf.len() < combining.max_bytes {
+        // timeout_at polls the channel before checking the deadline, so with
+        // zero timeout we still pick up replies which are already queued.
+        match tokio::time::timeout_at(deadline, reply_rx.recv()).await {
+            Ok(Some(msg)) => batch.push(&msg)?,
+            Ok(None) => return Ok(false),
+            Err(_) => break, // no new replies, flush what we have
+        }
+    }
+    Ok(true)
+}
+
 /// Read replies from WalAcceptor and pass them back to socket. Returns Ok(())
 /// if reply_rx closed; it must mean WalAcceptor terminated, joining it should
 /// tell the error.
 async fn network_write<IO: AsyncRead + AsyncWrite + Unpin>(
     pgb_writer: &mut PostgresBackend<IO>,
     mut reply_rx: Receiver<AcceptorProposerMessage>,
+    combining: ReplyCombining,
 ) -> Result<(), CopyStreamHandlerEnd> {
-    let mut buf = BytesMut::with_capacity(128);
+    let mut batch = ReplyBatch::default();
 
     loop {
-        match reply_rx.recv().await {
-            Some(msg) => {
-                buf.clear();
-                msg.serialize(&mut buf)?;
-                pgb_writer.write_message(&BeMessage::CopyData(&buf)).await?;
+        let chan_open = recv_reply_batch(&mut reply_rx, &combining, &mut batch).await?;
+        if !batch.ends.is_empty() {
+            for msg in batch.messages() {
+                pgb_writer.write_message_noflush(&BeMessage::CopyData(msg))?;
             }
-            None => return Ok(()), // chan closed, WalAcceptor terminated
+            pgb_writer.flush().await.map_err(ConnectionError::Io)?;
+        }
+        if !chan_open {
+            return Ok(()); // chan closed, WalAcceptor terminated
         }
     }
 }
@@ -213,11 +317,101 @@ async fn network_write<IO: AsyncRead + AsyncWrite + Unpin>(
 // even when it writes a steady stream of messages.
 const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Pushes replies to network_write. If network_write doesn't keep up and the
+/// queue is full, either waits for space or, with coalescing enabled, keeps
+/// only the latest AppendResponse aside instead of blocking: each
+/// AppendResponse supersedes the previous ones, so walproposer needs only the
+/// latest.
+struct ReplySender {
+    tx: Sender<AcceptorProposerMessage>,
+    coalesce: bool,
+    // latest AppendResponse which didn't fit into the queue
+    pending: Option<AcceptorProposerMessage>,
+}
+
+impl ReplySender {
+    fn new(tx: Sender<AcceptorProposerMessage>, coalesce: bool) -> Self {
+        ReplySender {
+            tx,
+            coalesce,
+            pending: None,
+        }
+    }
+
+    /// Push the reply. Returns false if the channel is closed.
+    async fn send(&mut self, reply: AcceptorProposerMessage) -> bool {
+        if !self.coalesce || !matches!(reply, AcceptorProposerMessage::AppendResponse(_)) {
+            // keep the order of replies
+            return self.send_pending().await && self.tx.send(reply).await.is_ok();
+        }
+
+        if let Some(pending) = self.pending.take() {
+            // if there is still no room, pending is superseded by reply
+            if let Err(TrySendError::Closed(_)) = self.tx.try_send(pending) {
+                return false;
+            }
+        }
+        match self.tx.try_send(reply) {
+            Ok(()) => true,
+            Err(TrySendError::Full(reply)) => {
+                self.pending = Some(reply);
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+
+    fn has_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Wait for room in the queue and push the pending reply, if any. Returns
+    /// false if the channel is closed. Cancellation safe.
+    async fn send_pending(&mut self) -> bool {
+        if self.pending.is_none() {
+            return true;
+        }
+        match self.tx.reserve().await {
+            Ok(permit) => {
+                if let Some(pending) = self.pending.take() {
+                    permit.send(pending);
+                }
+                true
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// Set on graceful shutdown, asking WalAcceptors to flush WAL they received
+/// and exit.
+static WAL_ACCEPTORS_SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Ask WalAcceptors to flush and acknowledge WAL they have received and exit,
+/// waiting at most `timeout` for them and for the acknowledgements to be
+/// written to the sockets.
+pub async fn shutdown_wal_acceptors(timeout: Duration) {
+    WAL_ACCEPTORS_SHUTDOWN.send_replace(true);
+    // each connection with WalAcceptor holds a receiver until the acceptor
+    // exits and its replies are written
+    if tokio::time::timeout(timeout, WAL_ACCEPTORS_SHUTDOWN.closed())
+        .await
+        .is_err()
+    {
+        warn!("WAL acceptors didn't shut down in {:?}", timeout);
+    }
+}
+
 /// Takes messages from msg_rx, processes and pushes replies to reply_tx.
 struct WalAcceptor {
     tli: Arc<Timeline>,
     msg_rx: Receiver<ProposerAcceptorMessage>,
-    reply_tx: Sender<AcceptorProposerMessage>,
+    reply_tx: ReplySender,
+    // Max number of AppendRequests processed without flushing, 0 means no
+    // limit.
+    max_append_batch: usize,
+    // Becomes true on graceful shutdown.
+    shutdown_rx: watch::Receiver<bool>,
 }
 
 impl WalAcceptor {
@@ -225,7 +419,9 @@ impl WalAcceptor {
     fn spawn(
         tli: Arc<Timeline>,
         msg_rx: Receiver<ProposerAcceptorMessage>,
-        reply_tx: Sender<AcceptorProposerMessage>,
+        reply_tx: ReplySender,
+        max_append_batch: usize,
+        shutdown_rx: watch::Receiver<bool>,
         conn_id: ConnectionId,
     ) -> JoinHandle<anyhow::Result<()>> {
         task::spawn(async move {
@@ -233,6 +429,8 @@ impl WalAcceptor {
                 tli,
                 msg_rx,
                 reply_tx,
+                max_append_batch,
+                shutdown_rx,
             };
 
             let span_ttid = wa.tli.ttid; // satisfy borrow checker
@@ -243,7 +441,8 @@ impl WalAcceptor {
     }
 
     /// The main loop. Returns Ok(()) if either msg_rx or reply_tx got closed;
-    /// it must mean that network thread terminated.
+    /// it must mean that network thread terminated. On graceful shutdown,
+    /// flushes received WAL and returns Ok(()).
     async fn run(&mut self) -> anyhow::Result<()> {
         // Register the connection and defer unregister.
         self.tli.on_compute_connect().await?;
@@ -256,25 +455,68 @@ impl WalAcceptor {
         // we will send keepalives by replying to these requests once per second.
         let mut next_keepalive = Instant::now();
 
+        if *self.shutdown_rx.borrow() {
+            return self.flush_on_shutdown().await;
+        }
+
+        let mut quiesce_rx = self.tli.subscribe_quiesce();
+        // Keepalives are AppendResponses, which proposer expects only once it
+        // started streaming.
+        let mut streaming = false;
         loop {
-            let opt_msg = self.msg_rx.recv().await;
+            let has_pending = self.reply_tx.has_pending();
+            // Everything received is flushed at this point, so if replication
+            // is quiesced, just hold on until it is resumed.
+            let quiesced = *quiesce_rx.borrow_and_update() > 0;
+            let opt_msg = tokio::select! {
+                msg = self.msg_rx.recv(), if !quiesced => msg,
+                // sender lives in the timeline, which we hold
+                Ok(()) = quiesce_rx.changed() => continue,
+                // no AppendRequests are answered while quiesced, so keep
+                // proposer from timing out the connection
+                _ = tokio::time::sleep_until(next_keepalive), if quiesced && streaming => {
+                    if let Some(reply) = self
+                        .tli
+                        .process_msg(&ProposerAcceptorMessage::FlushWAL)
+                        .await?
+                    {
+                        if !self.reply_tx.send(reply).await {
+                            return Ok(()); // chan closed, streaming terminated
+                        }
+                    }
+                    next_keepalive = Instant::now() + KEEPALIVE_INTERVAL;
+                    continue;
+                }
+                // push the coalesced reply once network_write catches up
+                chan_open = self.reply_tx.send_pending(), if has_pending => {
+                    if !chan_open {
+                        return Ok(()); // chan closed, streaming terminated
+                    }
+                    continue;
+                }
+                Ok(()) = self.shutdown_rx.changed() => {
+                    return self.flush_on_shutdown().await;
+                }
+            };
             if opt_msg.is_none() {
                 return Ok(()); // chan closed, streaming terminated
             }
             let mut next_msg = opt_msg.unwrap();
 
             let reply_msg = if matches!(next_msg, ProposerAcceptorMessage::AppendRequest(_)) {
+                streaming = true;
                 // loop through AppendRequest's while it's readily available to
                 // write as many WAL as possible without fsyncing
                 //
                 // Note: this will need to be rewritten if we want to read non-AppendRequest messages here.
                 // Otherwise, we might end up in a situation where we read a message, but don't
                 // process it.
+                let mut batch_len = 0;
                 while let ProposerAcceptorMessage::AppendRequest(append_request) = next_msg {
                     let noflush_msg = ProposerAcceptorMessage::NoFlushAppendRequest(append_request);
 
                     if let Some(reply) = self.tli.process_msg(&noflush_msg).await? {
-                        if self.reply_tx.send(reply).await.is_err() {
+                        if !self.reply_tx.send(reply).await {
                             return Ok(()); // chan closed, streaming terminated
                         }
                     }
@@ -284,6 +526,13 @@ impl WalAcceptor {
                         break;
                     }
 
+                    // or if the batch is big enough, so that proposer which
+                    // sends faster than we drain still gets replies
+                    batch_len += 1;
+                    if self.max_append_batch != 0 && batch_len >= self.max_append_batch {
+                        break;
+                    }
+
                     match self.msg_rx.try_recv() {
                         Ok(msg) => next_msg = msg,
                         Err(TryRecvError::Empty) => break,
@@ -301,7 +550,7 @@ impl WalAcceptor {
             };
 
             if let Some(reply) = reply_msg {
-                if self.reply_tx.send(reply).await.is_err() {
+                if !self.reply_tx.send(reply).await {
                     return Ok(()); // chan closed, streaming terminated
                 }
                 // reset keepalive time
@@ -309,6 +558,36 @@ impl WalAcceptor {
             }
         }
     }
+
+    /// Process messages already received, flush WAL and acknowledge it, so
+    /// that nothing proposer managed to send is lost on shutdown.
+    async fn flush_on_shutdown(&mut self) -> anyhow::Result<()> {
+        info!("shutting down, flushing received WAL");
+        while let Ok(msg) = self.msg_rx.try_recv() {
+            let msg = match msg {
+                ProposerAcceptorMessage::AppendRequest(append_request) => {
+                    ProposerAcceptorMessage::NoFlushAppendRequest(append_request)
+                }
+                msg => msg,
+            };
+            if let Some(reply) = self.tli.process_msg(&msg).await? {
+                if !self.reply_tx.send(reply).await {
+                    return Ok(()); // chan closed, streaming terminated
+                }
+            }
+        }
+
+        if let Some(reply) = self
+            .tli
+            .process_msg(&ProposerAcceptorMessage::FlushWAL)
+            .await?
+        {
+            if self.reply_tx.send(reply).await && self.reply_tx.has_pending() {
+                self.reply_tx.send_pending().await;
+            }
+        }
+        Ok(())
+    }
 }
 
 struct ComputeConnectionGuard {
@@ -327,3 +606,383 @@ impl Drop for ComputeConnectionGuard {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safekeeper::{
+        AppendRequest, AppendRequestHeader, AppendResponse, ProposerGreeting, SK_PROTOCOL_VERSION,
+    };
+    use crate::send_wal::HotStandbyFeedback;
+    use crate::test_utils::{
+        create_timeline_with_wal, init_global_timelines, logical_message, mock_connection,
+        raw_message, WAL_SEG_SIZE,
+    };
+    use bytes::{Buf, BufMut, Bytes};
+    use utils::bin_ser::LeSer;
+    use utils::pageserver_feedback::PageserverFeedback;
+
+    // Queue a burst of replies and count how many batches (i.e. flushes)
+    // network_write would do for them.
+    async fn count_batches(combining: ReplyCombining, n_replies: u64) -> usize {
+        let (reply_tx, mut reply_rx) = channel(n_replies as usize);
+        for term in 0..n_replies {
+            reply_tx.send(append_response(term)).await.unwrap();
+        }
+        drop(reply_tx);
+
+        let mut batch = ReplyBatch::default();
+        let mut n_batches = 0;
+        let mut n_msgs = 0;
+        loop {
+            let chan_open = recv_reply_batch(&mut reply_rx, &combining, &mut batch)
+                .await
+                .unwrap();
+            if !batch.ends.is_empty() {
+                n_batches += 1;
+                n_msgs += batch.messages().count();
+            }
+            if !chan_open {
+                break;
+            }
+        }
+        assert_eq!(n_msgs as u64, n_replies);
+        n_batches
+    }
+
+    fn append_response(term: u64) -> AcceptorProposerMessage {
+        AcceptorProposerMessage::AppendResponse(AppendResponse {
+            term,
+            flush_lsn: Lsn(0),
+            commit_lsn: Lsn(0),
+            hs_feedback: HotStandbyFeedback::empty(),
+            pageserver_feedback: PageserverFeedback::empty(),
+        })
+    }
+
+    fn term(reply: AcceptorProposerMessage) -> u64 {
+        match reply {
+            AcceptorProposerMessage::AppendResponse(reply) => reply.term,
+            _ => panic!("unexpected reply {reply:?}"),
+        }
+    }
+
+    // Create timeline with WAL written up to the given offset into the
+    // second segment, to have appends start in the middle of a page; returns
+    // the end of that WAL.
+    async fn create_timeline(offset: usize) -> (Arc<Timeline>, Lsn) {
+        let (_, tli, start_pos) = create_timeline_with_wal(&vec![0; offset]).await;
+        (tli, start_pos + offset as u64)
+    }
+
+    fn append_request(begin_lsn: Lsn, wal_data: Vec<u8>) -> ProposerAcceptorMessage {
+        ProposerAcceptorMessage::AppendRequest(AppendRequest {
+            h: AppendRequestHeader {
+                term: 0,
+                epoch_start_lsn: Lsn(0),
+                begin_lsn,
+                end_lsn: begin_lsn + wal_data.len() as u64,
+                commit_lsn: Lsn(0),
+                truncate_lsn: Lsn(0),
+                proposer_uuid: [0; 16],
+            },
+            wal_data: Bytes::from(wal_data),
+        })
+    }
+
+    // Run START_WAL_PUSH session through the whole handle_start_wal_push
+    // over in-memory connection: greeting creates the timeline, and appended
+    // WAL is acknowledged.
+    #[tokio::test]
+    async fn test_wal_push_session() {
+        let conf = init_global_timelines();
+        let ttid = TenantTimelineId::generate();
+        let (mut pgb, mut peer) = mock_connection();
+        let mut handler = SafekeeperPostgresHandler::new(conf, 1, None);
+        handler.ttid = ttid;
+        let wal_push = tokio::spawn(async move {
+            handler.handle_start_wal_push(&mut pgb).await.unwrap();
+        });
+        assert_eq!(peer.recv().await.unwrap().0, b'W'); // CopyBothResponse
+
+        let greeting = ProposerGreeting {
+            protocol_version: SK_PROTOCOL_VERSION,
+            pg_version: 150000,
+            proposer_id: [0; 16],
+            system_id: 0,
+            timeline_id: ttid.timeline_id,
+            tenant_id: ttid.tenant_id,
+            tli: 1,
+            wal_seg_size: WAL_SEG_SIZE as u32,
+        };
+        let mut msg = Vec::new();
+        msg.put_u64_le('g' as u64);
+        greeting.ser_into(&mut msg).unwrap();
+        peer.send_raw(&raw_message(b'd', &msg)).await;
+        let (tag, mut body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'd');
+        assert_eq!(body.get_u64_le(), 'g' as u64);
+        assert_eq!(body.get_u64_le(), 0); // term
+        assert!(GlobalTimelines::get(ttid).is_ok());
+
+        let begin_lsn = Lsn(WAL_SEG_SIZE as u64 + 0x100);
+        let wal_data = logical_message("prefix", "message");
+        let end_lsn = begin_lsn + wal_data.len() as u64;
+        let header = AppendRequestHeader {
+            term: 0,
+            epoch_start_lsn: Lsn(0),
+            begin_lsn,
+            end_lsn,
+            commit_lsn: Lsn(0),
+            truncate_lsn: Lsn(0),
+            proposer_uuid: [0; 16],
+        };
+        let mut msg = Vec::new();
+        msg.put_u64_le('a' as u64);
+        header.ser_into(&mut msg).unwrap();
+        msg.extend_from_slice(&wal_data);
+        peer.send_raw(&raw_message(b'd', &msg)).await;
+        let (tag, mut body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'd');
+        assert_eq!(body.get_u64_le(), 'a' as u64);
+        assert_eq!(body.get_u64_le(), 0); // term
+        assert_eq!(Lsn(body.get_u64_le()), end_lsn); // flush_lsn
+
+        // closing the connection ends the session
+        drop(peer);
+        tokio::time::timeout(Duration::from_secs(10), wal_push)
+            .await
+            .expect("WAL push session didn't end")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_max_message_size() {
+        let (mut pgb, mut peer) = mock_connection();
+        let mut pgb_reader = pgb.split().unwrap();
+        pgb_reader.set_max_message_len(1024);
+
+        // Send only the header of a huge CopyData: it must be refused right
+        // away, without waiting for (and buffering) the body.
+        let mut header = vec![b'd'];
+        header.extend_from_slice(&(1u32 << 30).to_be_bytes());
+        peer.send_raw(&header).await;
+        let res = tokio::time::timeout(Duration::from_secs(10), read_message(&mut pgb_reader))
+            .await
+            .expect("oversized message is not refused");
+        match res {
+            Err(CopyStreamHandlerEnd::Disconnected(ConnectionError::Protocol(e))) => {
+                assert!(e.to_string().contains("exceeds limit"), "{e}")
+            }
+            res => panic!("expected protocol error, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_quiesce() {
+        let (tli, begin_lsn) = create_timeline(0x100).await;
+        let quiesce_guard = tli.quiesce_replication();
+        let (msg_tx, msg_rx) = channel(100);
+        let (reply_tx, mut reply_rx) = channel(100);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let _acceptor = WalAcceptor::spawn(
+            tli.clone(),
+            msg_rx,
+            ReplySender::new(reply_tx, false),
+            0,
+            shutdown_rx,
+            0,
+        );
+
+        let wal_data = logical_message("prefix", "message");
+        let end_lsn = begin_lsn + wal_data.len() as u64;
+        msg_tx
+            .send(append_request(begin_lsn, wal_data))
+            .await
+            .unwrap();
+
+        // nothing is accepted while quiesced
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(reply_rx.try_recv().is_err());
+        assert_eq!(tli.get_flush_lsn().await, begin_lsn);
+
+        drop(quiesce_guard);
+        let reply = tokio::time::timeout(Duration::from_secs(10), reply_rx.recv())
+            .await
+            .expect("WAL is not accepted after quiesce")
+            .unwrap();
+        match reply {
+            AcceptorProposerMessage::AppendResponse(resp) => assert_eq!(resp.flush_lsn, end_lsn),
+            reply => panic!("unexpected reply {reply:?}"),
+        }
+    }
+
+    // test that proposer keeps getting keepalives while quiesced
+    #[tokio::test]
+    async fn test_quiesce_keepalive() {
+        let (tli, begin_lsn) = create_timeline(0x100).await;
+        let (msg_tx, msg_rx) = channel(100);
+        let (reply_tx, mut reply_rx) = channel(100);
+        let (_shutdown_tx, shutdown_rx) = watch::channel(false);
+        let _acceptor = WalAcceptor::spawn(
+            tli.clone(),
+            msg_rx,
+            ReplySender::new(reply_tx, false),
+            0,
+            shutdown_rx,
+            0,
+        );
+
+        let wal_data = logical_message("prefix", "message");
+        let end_lsn = begin_lsn + wal_data.len() as u64;
+        msg_tx
+            .send(append_request(begin_lsn, wal_data))
+            .await
+            .unwrap();
+        let reply = reply_rx.recv().await.unwrap();
+        assert!(matches!(reply, AcceptorProposerMessage::AppendResponse(_)));
+
+        let _quiesce_guard = tli.quiesce_replication();
+        for _ in 0..2 {
+            let reply = tokio::time::timeout(3 * KEEPALIVE_INTERVAL, reply_rx.recv())
+                .await
+                .expect("no keepalive while quiesced")
+                .unwrap();
+            match reply {
+                AcceptorProposerMessage::AppendResponse(resp) => {
+                    assert_eq!(resp.flush_lsn, end_lsn)
+                }
+                reply => panic!("unexpected reply {reply:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_shutdown() {
+        let (tli, mut end_lsn) = create_timeline(0x100).await;
+        let (msg_tx, msg_rx) = channel(100);
+        let (reply_tx, mut reply_rx) = channel(100);
+        let (shutdown_tx, shutdown_rx) = watch::channel(false);
+        let acceptor = WalAcceptor::spawn(
+            tli,
+            msg_rx,
+            ReplySender::new(reply_tx, false),
+            0,
+            shutdown_rx,
+            0,
+        );
+        // let it start waiting for messages
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        // Shutdown comes along with WAL which must not be lost; msg_tx is
+        // kept, so only shutdown can stop the acceptor.
+        for i in 0..5 {
+            let wal_data = logical_message("prefix", &format!("message {i}"));
+            let begin_lsn = end_lsn;
+            end_lsn += wal_data.len() as u64;
+            msg_tx
+                .try_send(append_request(begin_lsn, wal_data))
+                .unwrap();
+        }
+        shutdown_tx.send_replace(true);
+        acceptor.await.unwrap().unwrap();
+
+        let mut last_reply = None;
+        while let Ok(reply) = reply_rx.try_recv() {
+            last_reply = Some(reply);
+        }
+        match last_reply {
+            Some(AcceptorProposerMessage::AppendResponse(resp)) => {
+                assert_eq!(resp.flush_lsn, end_lsn)
+            }
+            reply => panic!("unexpected last reply {reply:?}"),
+        }
+        drop(msg_tx);
+    }
+
+    #[tokio::test]
+    async fn test_max_append_batch() {
+        const N_MSGS: usize = 25;
+        const MAX_BATCH: usize = 10;
+
+        let (tli, _) = create_timeline(0).await;
+
+        // Saturate the acceptor: all requests are readily available.
+        let (msg_tx, msg_rx) = channel(N_MSGS);
+        for _ in 0..N_MSGS {
+            msg_tx.send(append_request(Lsn(0), vec![])).await.unwrap();
+        }
+        drop(msg_tx);
+
+        let (reply_tx, mut reply_rx) = channel(N_MSGS);
+        WalAcceptor::spawn(
+            tli,
+            msg_rx,
+            ReplySender::new(reply_tx, false),
+            MAX_BATCH,
+            watch::channel(false).1,
+            0,
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        // Each flush produces a reply; the first request is flushed alone as
+        // keepalive is due immediately.
+        let mut n_flushes = 0;
+        while reply_rx.recv().await.is_some() {
+            n_flushes += 1;
+        }
+        assert!(
+            n_flushes >= 1 + (N_MSGS - 1 + MAX_BATCH - 1) / MAX_BATCH,
+            "only {n_flushes} flushes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_coalescing() {
+        let timeout = Duration::from_millis(100);
+
+        // Without coalescing, the second reply blocks until the first one is read.
+        let (reply_tx, _reply_rx) = channel(1);
+        let mut sender = ReplySender::new(reply_tx, false);
+        assert!(sender.send(append_response(0)).await);
+        assert!(
+            tokio::time::timeout(timeout, sender.send(append_response(1)))
+                .await
+                .is_err()
+        );
+
+        // With coalescing, replies which don't fit are collapsed into the latest.
+        let (reply_tx, mut reply_rx) = channel(1);
+        let mut sender = ReplySender::new(reply_tx, true);
+        for t in 0..5 {
+            assert!(
+                tokio::time::timeout(timeout, sender.send(append_response(t)))
+                    .await
+                    .unwrap()
+            );
+        }
+        assert!(sender.has_pending());
+        assert_eq!(term(reply_rx.recv().await.unwrap()), 0);
+        assert!(sender.send_pending().await);
+        assert!(!sender.has_pending());
+        assert_eq!(term(reply_rx.recv().await.unwrap()), 4);
+        assert!(reply_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reply_combining() {
+        let no_combining = ReplyCombining {
+            max_bytes: 0,
+            timeout: Duration::ZERO,
+        };
+        assert_eq!(count_batches(no_combining, 10).await, 10);
+
+        let combining = ReplyCombining {
+            max_bytes: 64 * 1024,
+            timeout: Duration::from_millis(10),
+        };
+        assert_eq!(count_batches(combining, 10).await, 1);
+    }
+}
@@ -24,7 +24,7 @@ pub async fn task_main(conf: SafeKeeperConf) -> anyhow::Result<()> {
                 warn!("failed to persist control file: {e}");
             }
             if let Err(e) = tli
-                .remove_old_wal(conf.wal_backup_enabled)
+                .remove_old_wal(conf.wal_backup_enabled, conf.replica_feedback_timeout)
                 .instrument(info_span!("", tenant = %ttid.tenant_id, timeline = %ttid.timeline_id))
                 .await
             {
@@ -2,18 +2,22 @@
 //! with the "START_REPLICATION" message, and registry of walsenders.
 
 use crate::handler::SafekeeperPostgresHandler;
+use crate::metrics::{WAL_SENDER_CAPPED_SENDS, WAL_SENDER_SENDS};
 use crate::timeline::Timeline;
 use crate::wal_service::ConnectionId;
 use crate::wal_storage::WalReader;
+use crate::wal_transport::WalTransport;
 use crate::GlobalTimelines;
-use anyhow::Context as AnyhowContext;
-use bytes::Bytes;
+use anyhow::{bail, Context as AnyhowContext};
+use bytes::{Bytes, BytesMut};
+use futures::{pin_mut, FutureExt, Stream, StreamExt};
 use parking_lot::Mutex;
 use postgres_backend::PostgresBackend;
 use postgres_backend::{CopyStreamHandlerEnd, PostgresBackendReader, QueryError};
-use postgres_ffi::get_current_timestamp;
+use postgres_ffi::{get_current_timestamp, to_pg_timestamp};
 use postgres_ffi::{TimestampTz, MAX_SEND_SIZE};
-use pq_proto::{BeMessage, WalSndKeepAlive, XLogDataBody};
+use pq_proto::framed::ConnectionError;
+use pq_proto::{BeMessage, FeMessage, ProtocolError, WalSndKeepAlive, XLogDataBody};
 use serde::{Deserialize, Serialize};
 use serde_with::{serde_as, DisplayFromStr};
 use tokio::io::{AsyncRead, AsyncWrite};
@@ -22,12 +26,15 @@ use utils::lsn::AtomicLsn;
 use utils::pageserver_feedback::PageserverFeedback;
 
 use std::cmp::{max, min};
+use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::str;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
 use tokio::sync::watch::Receiver;
-use tokio::time::timeout;
+use tokio::time::{timeout, Instant};
 use tracing::*;
 use utils::{bin_ser::BeSer, lsn::Lsn};
 
@@ -40,7 +47,7 @@ const NEON_STATUS_UPDATE_TAG_BYTE: u8 = b'z';
 type FullTransactionId = u64;
 
 /// Hot standby feedback received from replica
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct HotStandbyFeedback {
     pub ts: TimestampTz,
     pub xmin: FullTransactionId,
@@ -87,22 +94,45 @@ pub struct StandbyFeedback {
     hs_feedback: HotStandbyFeedback,
 }
 
+/// Aggregated pageserver and hot standby feedback, as published to
+/// subscribers of [`WalSenders::subscribe_feedbacks`].
+pub type AggregatedFeedbacks = (PageserverFeedback, HotStandbyFeedback);
+
 /// WalSenders registry. Timeline holds it (wrapped in Arc).
 pub struct WalSenders {
     /// Lsn maximized over all walsenders *and* peer data, so might be higher
     /// than what we receive from replicas.
     remote_consistent_lsn: AtomicLsn,
     mutex: Mutex<WalSendersShared>,
+    /// Aggregated feedbacks are published here whenever they change, so
+    /// consumers don't have to poll.
+    feedbacks_tx: watch::Sender<AggregatedFeedbacks>,
+    /// Generation bumped to ask all running walsenders to terminate.
+    terminate_tx: watch::Sender<u64>,
+    /// Walsenders waiting for commit_lsn to advance.
+    commit_lsn_waiters: CommitLsnWaiters,
 }
 
 impl WalSenders {
     pub fn new(remote_consistent_lsn: Lsn) -> Arc<WalSenders> {
+        let (feedbacks_tx, _) =
+            watch::channel((PageserverFeedback::empty(), HotStandbyFeedback::empty()));
+        let (terminate_tx, _) = watch::channel(0);
         Arc::new(WalSenders {
             remote_consistent_lsn: AtomicLsn::from(remote_consistent_lsn),
             mutex: Mutex::new(WalSendersShared::new()),
+            feedbacks_tx,
+            terminate_tx,
+            commit_lsn_waiters: CommitLsnWaiters::default(),
         })
     }
 
+    /// Wake up walsenders waiting for commit_lsn lower than the given one.
+    /// Must be called after the new value is published in commit_lsn watch.
+    pub fn notify_commit_lsn(self: &Arc<WalSenders>, commit_lsn: Lsn) {
+        self.commit_lsn_waiters.notify(commit_lsn);
+    }
+
     /// Register new walsender. Returned guard provides access to the slot and
     /// automatically deregisters in Drop.
     fn register(
@@ -119,6 +149,7 @@ impl WalSenders {
             conn_id,
             appname,
             feedback: ReplicationFeedback::Pageserver(PageserverFeedback::empty()),
+            position_reported_at: Instant::now(),
         };
         // find empty slot or create new one
         let pos = if let Some(pos) = slots.iter().position(|s| s.is_none()) {
@@ -140,6 +171,39 @@ impl WalSenders {
         self.mutex.lock().slots.iter().flatten().cloned().collect()
     }
 
+    /// Get state of all walsenders' receivers as reported in their feedback,
+    /// taken at once.
+    pub fn get_replica_snapshots(self: &Arc<WalSenders>) -> Vec<ReplicaSnapshot> {
+        self.mutex
+            .lock()
+            .slots
+            .iter()
+            .flatten()
+            .map(|ws_state| ws_state.replica_snapshot())
+            .collect()
+    }
+
+    /// Get position below which WAL isn't needed by any of the receivers:
+    /// minimum of their flush and apply LSNs, or Lsn::MAX if there are no
+    /// receivers. Receiver which hasn't reported its position yet might need
+    /// any WAL, so it holds the position at INVALID. Receivers which didn't
+    /// report their position during `feedback_timeout` (since connecting, if
+    /// they never did) are ignored, so a stuck one doesn't hold WAL forever.
+    pub fn get_min_replica_lsn(self: &Arc<WalSenders>, feedback_timeout: Duration) -> Lsn {
+        self.mutex
+            .lock()
+            .slots
+            .iter()
+            .flatten()
+            .filter(|ws_state| ws_state.position_reported_at.elapsed() <= feedback_timeout)
+            .map(|ws_state| {
+                let snapshot = ws_state.replica_snapshot();
+                min(snapshot.flush_lsn, snapshot.apply_lsn)
+            })
+            .min()
+            .unwrap_or(Lsn::MAX)
+    }
+
     /// Get aggregated pageserver feedback.
     pub fn get_ps_feedback(self: &Arc<WalSenders>) -> PageserverFeedback {
         self.mutex.lock().agg_ps_feedback
@@ -151,18 +215,45 @@ impl WalSenders {
         (shared.agg_ps_feedback, shared.agg_hs_feedback)
     }
 
+    /// Subscribe to changes of aggregated pageserver and hot standby feedback.
+    /// The channel is updated both when feedback arrives and when a walsender
+    /// goes away. Standby replies are not aggregated, but they wake up
+    /// subscribers as well, so walsenders can wait on it for apply progress.
+    pub fn subscribe_feedbacks(self: &Arc<WalSenders>) -> Receiver<AggregatedFeedbacks> {
+        self.feedbacks_tx.subscribe()
+    }
+
+    /// Publish aggregated feedbacks to subscribers, waking them only if the
+    /// value actually changed. Must be called under the lock to keep updates
+    /// ordered.
+    fn publish_feedbacks(&self, shared: &WalSendersShared) {
+        let new = (shared.agg_ps_feedback, shared.agg_hs_feedback);
+        self.feedbacks_tx.send_if_modified(|cur| {
+            if *cur == new {
+                false
+            } else {
+                *cur = new;
+                true
+            }
+        });
+    }
+
     /// Record new pageserver feedback, update aggregated values.
     fn record_ps_feedback(self: &Arc<WalSenders>, id: WalSenderId, feedback: &PageserverFeedback) {
         let mut shared = self.mutex.lock();
-        shared.get_slot_mut(id).feedback = ReplicationFeedback::Pageserver(*feedback);
+        let slot = shared.get_slot_mut(id);
+        slot.feedback = ReplicationFeedback::Pageserver(*feedback);
+        slot.position_reported_at = Instant::now();
         shared.update_ps_feedback();
         self.update_remote_consistent_lsn(shared.agg_ps_feedback.remote_consistent_lsn);
+        self.publish_feedbacks(&shared);
     }
 
     /// Record standby reply.
     fn record_standby_reply(self: &Arc<WalSenders>, id: WalSenderId, reply: &StandbyReply) {
         let mut shared = self.mutex.lock();
         let slot = shared.get_slot_mut(id);
+        slot.position_reported_at = Instant::now();
         match &mut slot.feedback {
             ReplicationFeedback::Standby(sf) => sf.reply = *reply,
             ReplicationFeedback::Pageserver(_) => {
@@ -172,6 +263,7 @@ impl WalSenders {
                 })
             }
         }
+        self.feedbacks_tx.send_modify(|_| {});
     }
 
     /// Record hot standby feedback, update aggregated value.
@@ -188,6 +280,7 @@ impl WalSenders {
             }
         }
         shared.update_hs_feedback();
+        self.publish_feedbacks(&shared);
     }
 
     /// Get remote_consistent_lsn reported by the pageserver. Returns None if
@@ -201,6 +294,16 @@ impl WalSenders {
         }
     }
 
+    /// Get apply position reported by the receiver. For pageserver this is
+    /// remote_consistent_lsn, as WAL before it is not needed anymore.
+    fn get_ws_apply_lsn(self: &Arc<WalSenders>, id: WalSenderId) -> Lsn {
+        let shared = self.mutex.lock();
+        match shared.get_slot(id).feedback {
+            ReplicationFeedback::Pageserver(feedback) => feedback.remote_consistent_lsn,
+            ReplicationFeedback::Standby(sf) => sf.reply.apply_lsn,
+        }
+    }
+
     /// Get remote_consistent_lsn maximized across all walsenders and peers.
     pub fn get_remote_consistent_lsn(self: &Arc<WalSenders>) -> Lsn {
         self.remote_consistent_lsn.load()
@@ -213,11 +316,51 @@ impl WalSenders {
             .max(candidate)
     }
 
+    /// Ask all currently running walsenders to stop streaming and exit, e.g.
+    /// because the timeline is being deleted. Walsenders started after the
+    /// call are not affected.
+    pub fn terminate_all(self: &Arc<WalSenders>) {
+        self.terminate_tx.send_modify(|generation| *generation += 1);
+    }
+
+    /// Register walproposer recovery stream, failing if another one is
+    /// already running on the timeline. Two proposers recovering from this
+    /// safekeeper at once means one of them is likely to garbage WAL streamed
+    /// to the other, so only one is allowed. Returned guard unregisters in
+    /// Drop.
+    ///
+    /// Note that the stream of a proposer which silently went away holds
+    /// the slot until writing to its socket fails, which may take as long as
+    /// TCP timeout; until then recovery of a new compute from this safekeeper
+    /// is rejected and walproposer has to retry on reconnection.
+    fn start_walproposer_recovery(
+        self: &Arc<WalSenders>,
+    ) -> anyhow::Result<WalproposerRecoveryGuard> {
+        let mut shared = self.mutex.lock();
+        if shared.walproposer_recovery_running {
+            bail!("walproposer recovery stream is already running on this timeline");
+        }
+        shared.walproposer_recovery_running = true;
+        Ok(WalproposerRecoveryGuard {
+            walsenders: self.clone(),
+        })
+    }
+
+    /// Subscribe to termination requests, see terminate_all.
+    fn subscribe_terminate(self: &Arc<WalSenders>) -> Receiver<u64> {
+        self.terminate_tx.subscribe()
+    }
+
     /// Unregister walsender.
     fn unregister(self: &Arc<WalSenders>, id: WalSenderId) {
         let mut shared = self.mutex.lock();
         shared.slots[id] = None;
         shared.update_hs_feedback();
+        shared.update_ps_feedback();
+        // Always wake subscribers on disconnect, even if aggregates are the
+        // same, so that anything tracking individual replicas isn't stale.
+        self.feedbacks_tx
+            .send_replace((shared.agg_ps_feedback, shared.agg_hs_feedback));
     }
 }
 
@@ -227,6 +370,9 @@ struct WalSendersShared {
     // aggregated over all walsenders value
     agg_ps_feedback: PageserverFeedback,
     slots: Vec<Option<WalSenderState>>,
+    // whether walproposer recovery stream is running, see
+    // start_walproposer_recovery
+    walproposer_recovery_running: bool,
 }
 
 impl WalSendersShared {
@@ -235,6 +381,7 @@ impl WalSendersShared {
             agg_hs_feedback: HotStandbyFeedback::empty(),
             agg_ps_feedback: PageserverFeedback::empty(),
             slots: Vec::new(),
+            walproposer_recovery_running: false,
         }
     }
 
@@ -322,6 +469,58 @@ pub struct WalSenderState {
     // postgres application_name
     appname: Option<String>,
     feedback: ReplicationFeedback,
+    // when the receiver last reported its position, or connected
+    #[serde(skip, default = "Instant::now")]
+    position_reported_at: Instant,
+}
+
+impl WalSenderState {
+    /// State of the receiver as reported in its feedback. Pageserver doesn't
+    /// apply WAL in the standby sense, so for it last_received_lsn is
+    /// reported as write and apply LSN, and disk_consistent_lsn as flush LSN.
+    pub fn replica_snapshot(&self) -> ReplicaSnapshot {
+        let (write_lsn, flush_lsn, apply_lsn, hs_feedback, last_reply_ts) = match self.feedback {
+            ReplicationFeedback::Pageserver(feedback) => (
+                feedback.last_received_lsn,
+                feedback.disk_consistent_lsn,
+                feedback.last_received_lsn,
+                HotStandbyFeedback::empty(),
+                to_pg_timestamp(feedback.replytime),
+            ),
+            ReplicationFeedback::Standby(feedback) => (
+                feedback.reply.write_lsn,
+                feedback.reply.flush_lsn,
+                feedback.reply.apply_lsn,
+                feedback.hs_feedback,
+                feedback.reply.reply_ts,
+            ),
+        };
+        ReplicaSnapshot {
+            appname: self.appname.clone(),
+            write_lsn,
+            flush_lsn,
+            apply_lsn,
+            xmin: hs_feedback.xmin,
+            catalog_xmin: hs_feedback.catalog_xmin,
+            last_reply_ts,
+        }
+    }
+}
+
+/// State of a single receiver as reported in its feedback.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicaSnapshot {
+    pub appname: Option<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub write_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub flush_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub apply_lsn: Lsn,
+    pub xmin: FullTransactionId,
+    pub catalog_xmin: FullTransactionId,
+    pub last_reply_ts: TimestampTz,
 }
 
 // Receiver is either pageserver or regular standby, which have different
@@ -351,11 +550,23 @@ impl Drop for WalSenderGuard {
     }
 }
 
+/// Scope guard of running walproposer recovery stream, see
+/// WalSenders::start_walproposer_recovery.
+struct WalproposerRecoveryGuard {
+    walsenders: Arc<WalSenders>,
+}
+
+impl Drop for WalproposerRecoveryGuard {
+    fn drop(&mut self) {
+        self.walsenders.mutex.lock().walproposer_recovery_running = false;
+    }
+}
+
 impl SafekeeperPostgresHandler {
     /// Wrapper around handle_start_replication_guts handling result. Error is
     /// handled here while we're still in walsender ttid span; with API
     /// extension, this can probably be moved into postgres_backend.
-    pub async fn handle_start_replication<IO: AsyncRead + AsyncWrite + Unpin>(
+    pub async fn handle_start_replication<IO: AsyncRead + AsyncWrite + Unpin + Send>(
         &mut self,
         pgb: &mut PostgresBackend<IO>,
         start_pos: Lsn,
@@ -367,7 +578,7 @@ impl SafekeeperPostgresHandler {
         Ok(())
     }
 
-    pub async fn handle_start_replication_guts<IO: AsyncRead + AsyncWrite + Unpin>(
+    pub async fn handle_start_replication_guts<IO: AsyncRead + AsyncWrite + Unpin + Send>(
         &mut self,
         pgb: &mut PostgresBackend<IO>,
         start_pos: Lsn,
@@ -376,6 +587,10 @@ impl SafekeeperPostgresHandler {
         let tli =
             GlobalTimelines::get(self.ttid).map_err(|e| CopyStreamHandlerEnd::Other(e.into()))?;
 
+        // Subscribe before registering, so that terminate_all issued once we
+        // are visible in the registry is never missed.
+        let mut terminate_rx = tli.get_walsenders().subscribe_terminate();
+
         // Use a guard object to remove our entry from the timeline when we are done.
         let ws_guard = Arc::new(tli.get_walsenders().register(
             self.ttid,
@@ -394,12 +609,17 @@ impl SafekeeperPostgresHandler {
         // There is a small risk of this WAL getting concurrently garbaged if
         // another compute rises which collects majority and starts fixing log
         // on this safekeeper itself. That's ok as (old) proposer will never be
-        // able to commit such WAL.
-        let stop_pos: Option<Lsn> = if self.is_walproposer_recovery() {
+        // able to commit such WAL. To narrow the window, only one recovery
+        // stream per timeline is allowed at a time.
+        let (stop_pos, _recovery_guard) = if self.is_walproposer_recovery() {
+            let recovery_guard = tli
+                .get_walsenders()
+                .start_walproposer_recovery()
+                .map_err(CopyStreamHandlerEnd::Other)?;
             let wal_end = tli.get_flush_lsn().await;
-            Some(wal_end)
+            (Some(wal_end), Some(recovery_guard))
         } else {
-            None
+            (None, None)
         };
 
         // take the latest commit_lsn if don't have stop_pos
@@ -427,14 +647,14 @@ impl SafekeeperPostgresHandler {
             &persisted_state,
             start_pos,
             self.conf.wal_backup_enabled,
+            self.conf.wal_reader_drop_cache,
         )?;
 
         // Split to concurrently receive and send data; replies are generally
         // not synchronized with sends, so this avoids deadlocks.
         let reader = pgb.split().context("START_REPLICATION split")?;
 
-        let mut sender = WalSender {
-            pgb,
+        let sender = WalSender {
             tli: tli.clone(),
             appname,
             start_pos,
@@ -443,25 +663,190 @@ impl SafekeeperPostgresHandler {
             commit_lsn_watch_rx,
             ws_guard: ws_guard.clone(),
             wal_reader,
-            send_buf: [0; MAX_SEND_SIZE],
+            send_buf: BytesMut::with_capacity(MAX_SEND_SIZE),
+            idle_timeout: self.conf.walsender_idle_timeout,
+            max_apply_lag: self.conf.walsender_max_apply_lag_bytes,
+            quiesce_rx: tli.subscribe_quiesce(),
+            last_wal_sent_at: Instant::now(),
+            capped_sends: CappedSends::new(),
+        };
+        let mut reply_reader = ReplyReader {
+            reader,
+            ws_guard,
+            lenient: self.conf.walsender_lenient_replies,
         };
-        let mut reply_reader = ReplyReader { reader, ws_guard };
 
-        let res = tokio::select! {
-            // todo: add read|write .context to these errors
-            r = sender.run() => r,
-            r = reply_reader.run() => r,
+        let res = {
+            let sender_fut = sender.run(pgb);
+            let reader_fut = reply_reader.run();
+            pin_mut!(sender_fut, reader_fut);
+            // If both halves finish at once, e.g. because the connection
+            // broke, the one which finished first might not tell the cause.
+            tokio::select! {
+                // todo: add read|write .context to these errors
+                outcome = &mut sender_fut => {
+                    outcome.log();
+                    pick_end(Err(outcome.into_stream_end()), (&mut reader_fut).now_or_never())
+                }
+                r = &mut reader_fut => {
+                    let sender_end = (&mut sender_fut)
+                        .now_or_never()
+                        .map(|outcome| Err(outcome.into_stream_end()));
+                    pick_end(r, sender_end)
+                }
+                end = wait_for_terminate(&mut terminate_rx) => Err(end),
+            }
         };
         // Join pg backend back.
-        pgb.unsplit(reply_reader.reader)?;
+        if let Err(e) = pgb.unsplit(reply_reader.reader) {
+            // Don't mask the reason of termination, likely the backend is
+            // broken because of it.
+            if res.is_err() {
+                warn!("failed to unsplit backend after streaming ended: {}", e);
+                return res;
+            }
+            return Err(e.into());
+        }
 
         res
     }
 }
 
+/// Choose the result of the streaming half which finished first, unless the
+/// other one also finished with a connection error: it is the root cause
+/// then, e.g. "caught up" termination of the sender doesn't matter if the
+/// receiver is gone.
+fn pick_end(
+    first: Result<(), CopyStreamHandlerEnd>,
+    other: Option<Result<(), CopyStreamHandlerEnd>>,
+) -> Result<(), CopyStreamHandlerEnd> {
+    let is_connection_error = |end: &CopyStreamHandlerEnd| {
+        matches!(
+            end,
+            CopyStreamHandlerEnd::Disconnected(_) | CopyStreamHandlerEnd::EOF
+        )
+    };
+    match (first, other) {
+        (Err(first), Some(Err(other)))
+            if !is_connection_error(&first) && is_connection_error(&other) =>
+        {
+            Err(other)
+        }
+        (first, _) => first,
+    }
+}
+
+/// Why WalSender stopped streaming.
+#[derive(Debug)]
+pub enum SenderOutcome {
+    /// Receiver is caught up and there are no computes, so no new WAL is
+    /// expected.
+    CaughtUpNoComputes { at: Lsn },
+    /// Streamed to walproposer everything up to stop_pos, recovery finished.
+    ReachedStopPos { at: Lsn },
+    /// No WAL was sent during idle_timeout.
+    IdleTimeout { at: Lsn, idle: Duration },
+    /// Receiver closed the connection or it broke, with the original EOF or
+    /// connection error.
+    ClientGone(CopyStreamHandlerEnd),
+    /// Streaming failed.
+    Error(CopyStreamHandlerEnd),
+}
+
+impl SenderOutcome {
+    /// Log why streaming stopped. Errors are not logged here, they are
+    /// reported along with the stream end.
+    fn log(&self) {
+        match self {
+            SenderOutcome::CaughtUpNoComputes { at } => {
+                info!("receiver caught up at {at} and there are no computes")
+            }
+            SenderOutcome::ReachedStopPos { at } => {
+                info!("walproposer recovery finished at {at}")
+            }
+            SenderOutcome::IdleTimeout { at, idle } => {
+                info!("no WAL was sent for {idle:?}, stopping at {at}")
+            }
+            SenderOutcome::ClientGone(end) => info!("receiver is gone: {end:#}"),
+            SenderOutcome::Error(_) => {}
+        }
+    }
+
+    /// Termination to report to the client: normal outcomes become
+    /// successful server initiated ends, not errors.
+    fn into_stream_end(self) -> CopyStreamHandlerEnd {
+        match self {
+            SenderOutcome::CaughtUpNoComputes { at } => CopyStreamHandlerEnd::ServerInitiated(
+                format!("ending streaming at {at}, receiver is caughtup and there is no computes"),
+            ),
+            SenderOutcome::ReachedStopPos { at } => CopyStreamHandlerEnd::ServerInitiated(format!(
+                "ending streaming to walproposer at {at}, recovery finished"
+            )),
+            SenderOutcome::IdleTimeout { at, idle } => CopyStreamHandlerEnd::ServerInitiated(
+                format!("ending streaming at {at}, no WAL was sent for {idle:?}"),
+            ),
+            SenderOutcome::ClientGone(end) => end,
+            SenderOutcome::Error(end) => end,
+        }
+    }
+}
+
+impl From<CopyStreamHandlerEnd> for SenderOutcome {
+    fn from(end: CopyStreamHandlerEnd) -> Self {
+        match end {
+            CopyStreamHandlerEnd::EOF | CopyStreamHandlerEnd::Disconnected(_) => {
+                SenderOutcome::ClientGone(end)
+            }
+            end => SenderOutcome::Error(end),
+        }
+    }
+}
+
+impl From<anyhow::Error> for SenderOutcome {
+    fn from(e: anyhow::Error) -> Self {
+        SenderOutcome::Error(CopyStreamHandlerEnd::Other(e))
+    }
+}
+
+/// Message to the receiver produced by WalSender.
+#[derive(Debug)]
+pub enum WalSenderMsg {
+    /// Piece of WAL starting at wal_start.
+    XLogData {
+        wal_start: Lsn,
+        wal_end: Lsn,
+        data: Bytes,
+    },
+    /// Keepalive requesting a reply, produced while waiting for WAL.
+    KeepAlive { wal_end: Lsn },
+}
+
+impl WalSenderMsg {
+    async fn write<T: WalTransport>(&self, transport: &mut T) -> Result<(), CopyStreamHandlerEnd> {
+        let msg = match self {
+            WalSenderMsg::XLogData {
+                wal_start,
+                wal_end,
+                data,
+            } => BeMessage::XLogData(XLogDataBody {
+                wal_start: wal_start.0,
+                wal_end: wal_end.0,
+                timestamp: get_current_timestamp(),
+                data,
+            }),
+            WalSenderMsg::KeepAlive { wal_end } => BeMessage::KeepAlive(WalSndKeepAlive {
+                wal_end: wal_end.0,
+                timestamp: get_current_timestamp(),
+                request_reply: true,
+            }),
+        };
+        transport.write_message_flush(&msg).await?;
+        Ok(())
+    }
+}
+
 /// A half driving sending WAL.
-struct WalSender<'a, IO> {
-    pgb: &'a mut PostgresBackend<IO>,
+struct WalSender {
     tli: Arc<Timeline>,
     appname: Option<String>,
     // Position since which we are sending next chunk.
@@ -480,108 +865,267 @@ struct WalSender<'a, IO> {
     ws_guard: Arc<WalSenderGuard>,
     wal_reader: WalReader,
     // buffer for readling WAL into to send it
-    send_buf: [u8; MAX_SEND_SIZE],
+    send_buf: BytesMut,
+    // If set, terminate once no WAL was sent for this long, so abandoned
+    // receivers don't hold connections forever.
+    idle_timeout: Option<Duration>,
+    last_wal_sent_at: Instant,
+    // If set, pause sending once we are this many bytes ahead of the apply
+    // position reported by the receiver, so slow replica doesn't make us
+    // read WAL far ahead of it.
+    max_apply_lag: Option<u64>,
+    // Number of alive QuiesceGuards of the timeline, sending is paused
+    // while it is not zero.
+    quiesce_rx: Receiver<usize>,
+    capped_sends: CappedSends,
 }
 
-impl<IO: AsyncRead + AsyncWrite + Unpin> WalSender<'_, IO> {
+impl WalSender {
     /// Send WAL until
     /// - an error occurs
     /// - if we are streaming to walproposer, we've streamed until stop_pos
     ///   (recovery finished)
     /// - receiver is caughtup and there is no computes
+    /// - no WAL was sent during idle_timeout
     ///
-    /// Err(CopyStreamHandlerEnd) is always returned; Result is used only for ?
-    /// convenience.
-    async fn run(&mut self) -> Result<(), CopyStreamHandlerEnd> {
-        loop {
-            // If we are streaming to walproposer, check it is time to stop.
-            if let Some(stop_pos) = self.stop_pos {
-                if self.start_pos >= stop_pos {
-                    // recovery finished
-                    return Err(CopyStreamHandlerEnd::ServerInitiated(format!(
-                        "ending streaming to walproposer at {}, recovery finished",
-                        self.start_pos
-                    )));
+    /// Sending is paused while the receiver lags behind more than max_apply_lag.
+    async fn run<T: WalTransport>(self, transport: &mut T) -> SenderOutcome {
+        let msgs = self.into_stream();
+        pin_mut!(msgs);
+        while let Some(msg) = msgs.next().await {
+            let msg = match msg {
+                Ok(msg) => msg,
+                Err(outcome) => return outcome,
+            };
+            if let Err(end) = msg.write(transport).await {
+                return end.into();
+            }
+        }
+        // can't happen, the stream ends only after yielding the outcome
+        anyhow::anyhow!("WAL stream ended without outcome").into()
+    }
+
+    /// Messages to the receiver as a stream. It ends after yielding the first
+    /// error, which is the outcome of streaming.
+    fn into_stream(mut self) -> impl Stream<Item = Result<WalSenderMsg, SenderOutcome>> {
+        async_stream::stream! {
+            loop {
+                let res = self.next_msg().await;
+                let is_end = res.is_err();
+                yield res;
+                if is_end {
+                    break;
                 }
-            } else {
-                // Wait for the next portion if it is not there yet, or just
-                // update our end of WAL available for sending value, we
-                // communicate it to the receiver.
-                self.wait_wal().await?;
             }
+        }
+    }
+
+    /// Produce the next message: piece of WAL if it is available, or
+    /// keepalive if nothing appears for a while.
+    async fn next_msg(&mut self) -> Result<WalSenderMsg, SenderOutcome> {
+        // If we are streaming to walproposer, check it is time to stop.
+        if let Some(stop_pos) = self.stop_pos {
+            if self.start_pos >= stop_pos {
+                // recovery finished
+                return Err(SenderOutcome::ReachedStopPos { at: self.start_pos });
+            }
+        } else {
+            // Wait for the next portion if it is not there yet, or just
+            // update our end of WAL available for sending value, we
+            // communicate it to the receiver.
+            if !self.wait_wal().await? {
+                return Ok(WalSenderMsg::KeepAlive {
+                    wal_end: self.keepalive_wal_end().await,
+                });
+            }
+        }
+        if !self.wait_apply().await || !self.wait_unquiesced().await {
+            return Ok(WalSenderMsg::KeepAlive {
+                wal_end: self.keepalive_wal_end().await,
+            });
+        }
 
-            // try to send as much as available, capped by MAX_SEND_SIZE
-            let mut send_size = self
-                .end_pos
-                .checked_sub(self.start_pos)
-                .context("reading wal without waiting for it first")?
-                .0 as usize;
-            send_size = min(send_size, self.send_buf.len());
-            let send_buf = &mut self.send_buf[..send_size];
-            // read wal into buffer
-            send_size = self.wal_reader.read(send_buf).await?;
-            let send_buf = &send_buf[..send_size];
-
-            // and send it
-            self.pgb
-                .write_message(&BeMessage::XLogData(XLogDataBody {
-                    wal_start: self.start_pos.0,
-                    wal_end: self.end_pos.0,
-                    timestamp: get_current_timestamp(),
-                    data: send_buf,
-                }))
-                .await?;
+        // try to send as much as available, capped by MAX_SEND_SIZE
+        let available = self
+            .end_pos
+            .checked_sub(self.start_pos)
+            .context("reading wal without waiting for it first")?
+            .0 as usize;
+        let send_size = min(available, MAX_SEND_SIZE);
+        self.capped_sends
+            .record(available > MAX_SEND_SIZE, &self.appname);
+        // read wal into the spare capacity of the buffer, without zeroing it;
+        // reserve reclaims the allocation once the previous message is sent
+        self.send_buf.reserve(send_size);
+        let send_size = self.wal_reader.read(&mut self.send_buf, send_size).await?;
+
+        let msg = WalSenderMsg::XLogData {
+            wal_start: self.start_pos,
+            wal_end: self.end_pos,
+            data: self.send_buf.split().freeze(),
+        };
+        trace!(
+            "sending {} bytes of WAL {}-{}",
+            send_size,
+            self.start_pos,
+            self.start_pos + send_size as u64
+        );
+        self.start_pos += send_size as u64;
+        self.last_wal_sent_at = Instant::now();
+        Ok(msg)
+    }
 
+    /// End of WAL to report in keepalive. Pageserver takes it as commit_lsn,
+    /// so it is the latest commit_lsn, which also matches end of XLogData
+    /// messages. Only walproposer in recovery streams uncommitted WAL, and
+    /// it is told the flush_lsn.
+    async fn keepalive_wal_end(&self) -> Lsn {
+        if self.stop_pos.is_some() {
+            self.tli.get_flush_lsn().await
+        } else {
+            *self.commit_lsn_watch_rx.borrow()
+        }
+    }
+
+    /// Wait until the receiver applies WAL close enough to what we've sent.
+    /// Returns false if it still lags behind after a while and it is time to
+    /// send a keepalive, which also asks the receiver for fresh feedback. No
+    /// throttling happens until the receiver reports its apply position.
+    async fn wait_apply(&self) -> bool {
+        let max_apply_lag = match self.max_apply_lag {
+            Some(max_apply_lag) => max_apply_lag,
+            None => return true,
+        };
+        let walsenders = &self.ws_guard.walsenders;
+        // subscribe before checking to not miss feedback arriving in between
+        let mut feedbacks_rx = walsenders.subscribe_feedbacks();
+        let caught_up = async {
+            loop {
+                let apply_lsn = walsenders.get_ws_apply_lsn(self.ws_guard.id);
+                if apply_lsn == Lsn::INVALID
+                    || self.start_pos.0.saturating_sub(apply_lsn.0) <= max_apply_lag
+                {
+                    break;
+                }
+                // walsenders live in the timeline, which we hold
+                if feedbacks_rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        };
+        if timeout(POLL_STATE_TIMEOUT, caught_up).await.is_err() {
             trace!(
-                "sent {} bytes of WAL {}-{}",
-                send_size,
-                self.start_pos,
-                self.start_pos + send_size as u64
+                "receiver applied only up to {}, {} sent, pausing",
+                walsenders.get_ws_apply_lsn(self.ws_guard.id),
+                self.start_pos
             );
-            self.start_pos += send_size as u64;
+            return false;
         }
+        true
     }
 
-    /// wait until we have WAL to stream, sending keepalives and checking for
-    /// exit in the meanwhile
-    async fn wait_wal(&mut self) -> Result<(), CopyStreamHandlerEnd> {
-        loop {
-            self.end_pos = *self.commit_lsn_watch_rx.borrow();
-            if self.end_pos > self.start_pos {
-                // We have something to send.
-                trace!("got end_pos {:?}, streaming", self.end_pos);
-                return Ok(());
+    /// Wait until replication of the timeline is resumed, if it is quiesced.
+    /// Returns false if it is still paused after a while and it is time to
+    /// send a keepalive.
+    async fn wait_unquiesced(&mut self) -> bool {
+        let quiesce_rx = &mut self.quiesce_rx;
+        let unquiesced = async {
+            while *quiesce_rx.borrow_and_update() > 0 {
+                // sender lives in the timeline, which we hold
+                if quiesce_rx.changed().await.is_err() {
+                    break;
+                }
             }
+        };
+        timeout(POLL_STATE_TIMEOUT, unquiesced).await.is_ok()
+    }
 
-            // Wait for WAL to appear, now self.end_pos == self.start_pos.
-            if let Some(lsn) = wait_for_lsn(&mut self.commit_lsn_watch_rx, self.start_pos).await? {
-                self.end_pos = lsn;
-                trace!("got end_pos {:?}, streaming", self.end_pos);
-                return Ok(());
-            }
+    /// Wait until we have WAL to stream, checking for exit in the meanwhile.
+    /// Returns false if nothing appeared for a while and it is time to send
+    /// a keepalive.
+    async fn wait_wal(&mut self) -> Result<bool, SenderOutcome> {
+        self.end_pos = *self.commit_lsn_watch_rx.borrow();
+        if self.end_pos > self.start_pos {
+            // We have something to send.
+            trace!("got end_pos {:?}, streaming", self.end_pos);
+            return Ok(true);
+        }
 
-            // Timed out waiting for WAL, check for termination and send KA
-            if let Some(remote_consistent_lsn) = self
-                .ws_guard
-                .walsenders
-                .get_ws_remote_consistent_lsn(self.ws_guard.id)
-            {
-                if self.tli.should_walsender_stop(remote_consistent_lsn).await {
-                    // Terminate if there is nothing more to send.
-                    return Err(CopyStreamHandlerEnd::ServerInitiated(format!(
-                        "ending streaming to {:?} at {}, receiver is caughtup and there is no computes",
-                        self.appname, self.start_pos,
-                    )));
-                }
+        // Wait for WAL to appear, now self.end_pos == self.start_pos.
+        if let Some(lsn) = wait_for_lsn(
+            &self.ws_guard.walsenders,
+            &self.commit_lsn_watch_rx,
+            self.start_pos,
+        )
+        .await?
+        {
+            self.end_pos = lsn;
+            trace!("got end_pos {:?}, streaming", self.end_pos);
+            return Ok(true);
+        }
+
+        // Timed out waiting for WAL, check for termination and send KA
+        if let Some(idle_timeout) = self.idle_timeout {
+            let idle = self.last_wal_sent_at.elapsed();
+            if idle >= idle_timeout {
+                return Err(SenderOutcome::IdleTimeout {
+                    at: self.start_pos,
+                    idle,
+                });
             }
+        }
+        if let Some(remote_consistent_lsn) = self
+            .ws_guard
+            .walsenders
+            .get_ws_remote_consistent_lsn(self.ws_guard.id)
+        {
+            if self.tli.should_walsender_stop(remote_consistent_lsn).await {
+                // Terminate if there is nothing more to send.
+                return Err(SenderOutcome::CaughtUpNoComputes { at: self.start_pos });
+            }
+        }
+        Ok(false)
+    }
+}
+
+// How often to check whether walsender is limited by MAX_SEND_SIZE.
+const CAPPED_SENDS_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Counts sends capped by MAX_SEND_SIZE rather than by available WAL. If
+/// this happens most of the time, the sender is limited by the send size,
+/// not by WAL production.
+struct CappedSends {
+    sends: u64,
+    capped: u64,
+    since: Instant,
+}
 
-            self.pgb
-                .write_message(&BeMessage::KeepAlive(WalSndKeepAlive {
-                    wal_end: self.end_pos.0,
-                    timestamp: get_current_timestamp(),
-                    request_reply: true,
-                }))
-                .await?;
+impl CappedSends {
+    fn new() -> Self {
+        CappedSends {
+            sends: 0,
+            capped: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, capped: bool, appname: &Option<String>) {
+        WAL_SENDER_SENDS.inc();
+        self.sends += 1;
+        if capped {
+            WAL_SENDER_CAPPED_SENDS.inc();
+            self.capped += 1;
+        }
+
+        let elapsed = self.since.elapsed();
+        if elapsed >= CAPPED_SENDS_LOG_INTERVAL {
+            if self.capped * 10 >= self.sends * 9 {
+                info!(
+                    "{} of {} WAL sends to {:?} during last {:?} were capped by send size {}, larger send size would help",
+                    self.capped, self.sends, appname, elapsed, MAX_SEND_SIZE
+                );
+            }
+            *self = CappedSends::new();
         }
     }
 }
@@ -590,12 +1134,33 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> WalSender<'_, IO> {
 struct ReplyReader<IO> {
     reader: PostgresBackendReader<IO>,
     ws_guard: Arc<WalSenderGuard>,
+    /// Ignore Sync and Flush messages instead of failing the stream. They
+    /// make no sense in COPY mode, but some clients send them anyway, and
+    /// they don't require any response.
+    lenient: bool,
 }
 
 impl<IO: AsyncRead + AsyncWrite + Unpin> ReplyReader<IO> {
     async fn run(&mut self) -> Result<(), CopyStreamHandlerEnd> {
         loop {
-            let msg = self.reader.read_copy_message().await?;
+            let msg = match self.reader.read_message().await? {
+                Some(FeMessage::CopyData(msg)) => msg,
+                Some(msg @ (FeMessage::Sync | FeMessage::Flush)) if self.lenient => {
+                    warn!("ignoring unexpected message in COPY stream {:?}", msg);
+                    continue;
+                }
+                Some(FeMessage::CopyDone) => return Err(CopyStreamHandlerEnd::CopyDone),
+                Some(FeMessage::CopyFail) => return Err(CopyStreamHandlerEnd::CopyFail),
+                Some(FeMessage::Terminate) => return Err(CopyStreamHandlerEnd::Terminate),
+                Some(msg) => {
+                    return Err(ConnectionError::Protocol(ProtocolError::Protocol(format!(
+                        "unexpected message in COPY stream {:?}",
+                        msg
+                    )))
+                    .into())
+                }
+                None => return Err(CopyStreamHandlerEnd::EOF),
+            };
             self.handle_feedback(&msg)?
         }
     }
@@ -637,32 +1202,93 @@ impl<IO: AsyncRead + AsyncWrite + Unpin> ReplyReader<IO> {
     }
 }
 
+/// Resolves once termination of walsenders is requested through
+/// WalSenders::terminate_all.
+async fn wait_for_terminate(rx: &mut Receiver<u64>) -> CopyStreamHandlerEnd {
+    // Sender lives in WalSenders which we keep alive through the guard, so
+    // error can't happen; wait forever if it somehow does.
+    if rx.changed().await.is_err() {
+        futures::future::pending::<()>().await;
+    }
+    CopyStreamHandlerEnd::ServerInitiated("walsender terminated by request".to_string())
+}
+
 const POLL_STATE_TIMEOUT: Duration = Duration::from_secs(1);
 
+/// Walsenders waiting for commit_lsn to pass their positions. Unlike
+/// commit_lsn watch waking up everyone on each advance, a waiter is woken up
+/// only once commit_lsn passes its position, so many walsenders at different
+/// positions don't spin on each commit.
+#[derive(Default)]
+struct CommitLsnWaiters {
+    inner: Mutex<CommitLsnWaitersInner>,
+}
+
+#[derive(Default)]
+struct CommitLsnWaitersInner {
+    next_id: u64,
+    // keyed by (lsn waited for, waiter id)
+    waiters: BTreeMap<(Lsn, u64), oneshot::Sender<()>>,
+}
+
+impl CommitLsnWaiters {
+    /// Register waiter for commit_lsn > lsn. The receiver resolves once it is
+    /// reached; the waiter is deregistered when the guard is dropped.
+    fn register(&self, lsn: Lsn) -> (CommitLsnWaiterGuard<'_>, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        let mut inner = self.inner.lock();
+        let key = (lsn, inner.next_id);
+        inner.next_id += 1;
+        inner.waiters.insert(key, tx);
+        (CommitLsnWaiterGuard { waiters: self, key }, rx)
+    }
+
+    /// Wake up waiters which wait for lsn < commit_lsn.
+    fn notify(&self, commit_lsn: Lsn) {
+        let satisfied = {
+            let mut inner = self.inner.lock();
+            let pending = inner.waiters.split_off(&(commit_lsn, 0));
+            std::mem::replace(&mut inner.waiters, pending)
+        };
+        for (_, tx) in satisfied {
+            let _ = tx.send(()); // waiter might be gone already
+        }
+    }
+}
+
+struct CommitLsnWaiterGuard<'a> {
+    waiters: &'a CommitLsnWaiters,
+    key: (Lsn, u64),
+}
+
+impl Drop for CommitLsnWaiterGuard<'_> {
+    fn drop(&mut self) {
+        self.waiters.inner.lock().waiters.remove(&self.key);
+    }
+}
+
 /// Wait until we have commit_lsn > lsn or timeout expires. Returns
 /// - Ok(Some(commit_lsn)) if needed lsn is successfully observed;
 /// - Ok(None) if timeout expired;
-/// - Err in case of error (if watch channel is in trouble, shouldn't happen).
-async fn wait_for_lsn(rx: &mut Receiver<Lsn>, lsn: Lsn) -> anyhow::Result<Option<Lsn>> {
-    let res = timeout(POLL_STATE_TIMEOUT, async move {
-        let mut commit_lsn;
-        loop {
-            rx.changed().await?;
-            commit_lsn = *rx.borrow();
-            if commit_lsn > lsn {
-                break;
-            }
-        }
-
-        Ok(commit_lsn)
-    })
-    .await;
+/// - Err in case of error (shouldn't happen).
+async fn wait_for_lsn(
+    walsenders: &WalSenders,
+    rx: &Receiver<Lsn>,
+    lsn: Lsn,
+) -> anyhow::Result<Option<Lsn>> {
+    let (_guard, woken) = walsenders.commit_lsn_waiters.register(lsn);
+    // Check only after registering: commit_lsn is published before waiters
+    // are notified, so the advance is either seen here or wakes us up.
+    let commit_lsn = *rx.borrow();
+    if commit_lsn > lsn {
+        return Ok(Some(commit_lsn));
+    }
 
-    match res {
+    match timeout(POLL_STATE_TIMEOUT, woken).await {
         // success
-        Ok(Ok(commit_lsn)) => Ok(Some(commit_lsn)),
-        // error inside closure
-        Ok(Err(err)) => Err(err),
+        Ok(Ok(())) => Ok(Some(*rx.borrow())),
+        // waiter can't be removed while we hold the guard
+        Ok(Err(_)) => Err(anyhow::anyhow!("commit_lsn waiter dropped")),
         // timeout
         Err(_) => Ok(None),
     }
@@ -670,9 +1296,17 @@ async fn wait_for_lsn(rx: &mut Receiver<Lsn>, lsn: Lsn) -> anyhow::Result<Option
 
 #[cfg(test)]
 mod tests {
+    use bytes::Buf;
+    use postgres_ffi::{XLogFileName, PG_TLI};
     use postgres_protocol::PG_EPOCH;
+    use tokio::io::DuplexStream;
     use utils::id::{TenantId, TimelineId};
 
+    use crate::control_file::Storage;
+    use crate::test_utils::{
+        create_timeline_with_wal, mock_connection, raw_message, MockPeer, WAL_SEG_SIZE,
+    };
+
     use super::*;
 
     fn mock_ttid() -> TenantTimelineId {
@@ -694,6 +1328,7 @@ mod tests {
             conn_id: 1,
             appname: None,
             feedback,
+            position_reported_at: Instant::now(),
         };
         wss.slots.push(Some(walsender_state))
     }
@@ -752,4 +1387,919 @@ mod tests {
         assert_eq!(wss.agg_ps_feedback.current_timeline_size, 4);
         assert_eq!(wss.agg_ps_feedback.last_received_lsn, Lsn(84));
     }
+
+    // test that terminate_all wakes up walsenders running at the moment of
+    // the call, but not the ones started later
+    #[tokio::test]
+    async fn test_terminate_all() {
+        let wss = WalSenders::new(Lsn(0));
+        let mut rx = wss.subscribe_terminate();
+        let waiter = tokio::spawn(async move { wait_for_terminate(&mut rx).await });
+
+        wss.terminate_all();
+        let end = timeout(Duration::from_secs(10), waiter)
+            .await
+            .expect("walsender didn't terminate")
+            .unwrap();
+        assert!(matches!(end, CopyStreamHandlerEnd::ServerInitiated(_)));
+
+        let mut rx = wss.subscribe_terminate();
+        assert!(!rx.has_changed().unwrap());
+        assert!(
+            timeout(Duration::from_millis(10), wait_for_terminate(&mut rx))
+                .await
+                .is_err()
+        );
+    }
+
+    async fn run_reply_reader(
+        lenient: bool,
+        messages: &[Vec<u8>],
+    ) -> (Result<(), CopyStreamHandlerEnd>, ReplyReader<DuplexStream>) {
+        let (mut pgb, mut peer) = mock_connection();
+        let wss = WalSenders::new(Lsn(0));
+        let mut reply_reader = ReplyReader {
+            reader: pgb.split().unwrap(),
+            ws_guard: Arc::new(wss.register(mock_ttid(), mock_addr(), 1, None)),
+            lenient,
+        };
+        for msg in messages {
+            peer.send_raw(msg).await;
+        }
+        let res = timeout(Duration::from_secs(10), reply_reader.run())
+            .await
+            .expect("reply reader didn't finish");
+        (res, reply_reader)
+    }
+
+    #[tokio::test]
+    async fn test_reply_reader_lenient() {
+        let reply = StandbyReply {
+            write_lsn: Lsn(0x200),
+            flush_lsn: Lsn(0x200),
+            apply_lsn: Lsn(0x100),
+            reply_ts: 0,
+            reply_requested: false,
+        };
+        let mut feedback = vec![STANDBY_STATUS_UPDATE_TAG_BYTE];
+        feedback.extend_from_slice(&reply.ser().unwrap());
+        let messages = [
+            raw_message(b'S', &[]),
+            raw_message(b'H', &[]),
+            raw_message(b'd', &feedback),
+            raw_message(b'c', &[]),
+        ];
+
+        // Sync and Flush are skipped, feedback after them is still processed.
+        let (res, reply_reader) = run_reply_reader(true, &messages).await;
+        assert!(
+            matches!(res, Err(CopyStreamHandlerEnd::CopyDone)),
+            "{res:?}"
+        );
+        let walsenders = &reply_reader.ws_guard.walsenders;
+        assert_eq!(walsenders.get_min_replica_lsn(Duration::MAX), Lsn(0x100));
+
+        // Messages which are never fine in COPY mode still fail the stream.
+        let (res, _) = run_reply_reader(true, &[raw_message(b'Q', b"SELECT 1\0")]).await;
+        assert!(
+            matches!(
+                res,
+                Err(CopyStreamHandlerEnd::Disconnected(
+                    ConnectionError::Protocol(_)
+                ))
+            ),
+            "{res:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reply_reader_strict() {
+        let (res, reply_reader) = run_reply_reader(false, &[raw_message(b'S', &[])]).await;
+        assert!(
+            matches!(
+                res,
+                Err(CopyStreamHandlerEnd::Disconnected(
+                    ConnectionError::Protocol(_)
+                ))
+            ),
+            "{res:?}"
+        );
+        let walsenders = &reply_reader.ws_guard.walsenders;
+        assert_eq!(walsenders.get_min_replica_lsn(Duration::MAX), Lsn::INVALID);
+    }
+
+    #[test]
+    fn test_pick_end() {
+        let caught_up = || {
+            Err(CopyStreamHandlerEnd::ServerInitiated(
+                "caught up".to_string(),
+            ))
+        };
+        let disconnected = || {
+            Err(CopyStreamHandlerEnd::Disconnected(ConnectionError::Io(
+                std::io::Error::from(std::io::ErrorKind::ConnectionReset),
+            )))
+        };
+
+        // connection error explains the other one
+        assert!(matches!(
+            pick_end(caught_up(), Some(disconnected())),
+            Err(CopyStreamHandlerEnd::Disconnected(_))
+        ));
+        assert!(matches!(
+            pick_end(caught_up(), Some(Err(CopyStreamHandlerEnd::EOF))),
+            Err(CopyStreamHandlerEnd::EOF)
+        ));
+        // otherwise the first one wins
+        assert!(matches!(
+            pick_end(disconnected(), Some(caught_up())),
+            Err(CopyStreamHandlerEnd::Disconnected(_))
+        ));
+        assert!(matches!(
+            pick_end(caught_up(), Some(Err(CopyStreamHandlerEnd::CopyDone))),
+            Err(CopyStreamHandlerEnd::ServerInitiated(_))
+        ));
+        assert!(matches!(
+            pick_end(caught_up(), None),
+            Err(CopyStreamHandlerEnd::ServerInitiated(_))
+        ));
+    }
+
+    // Create walsender for the given WAL placed at the beginning of the
+    // second segment; returns it along with the start of the WAL.
+    async fn wal_sender(
+        wal: &[u8],
+        stop_at_end: bool,
+        idle_timeout: Option<Duration>,
+    ) -> (WalSender, Lsn) {
+        let (conf, tli, start_pos) = create_timeline_with_wal(wal).await;
+        let end_pos = start_pos + wal.len() as u64;
+        let (_, state) = tli.get_state().await;
+        let wal_reader = WalReader::new(
+            conf.workdir.clone(),
+            conf.timeline_dir(&tli.ttid),
+            &state,
+            start_pos,
+            false,
+            false,
+        )
+        .unwrap();
+
+        let sender = WalSender {
+            tli: tli.clone(),
+            appname: None,
+            start_pos,
+            end_pos,
+            stop_pos: stop_at_end.then_some(end_pos),
+            commit_lsn_watch_rx: tli.get_commit_lsn_watch_rx(),
+            ws_guard: Arc::new(
+                tli.get_walsenders()
+                    .register(tli.ttid, mock_addr(), 1, None),
+            ),
+            wal_reader,
+            send_buf: BytesMut::new(),
+            idle_timeout,
+            last_wal_sent_at: Instant::now(),
+            max_apply_lag: None,
+            quiesce_rx: tli.subscribe_quiesce(),
+            capped_sends: CappedSends::new(),
+        };
+        (sender, start_pos)
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_stream() {
+        // A few sends worth of WAL.
+        let wal: Vec<u8> = (0..3 * MAX_SEND_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let (sender, start_pos) = wal_sender(&wal, true, None).await;
+        let end_pos = start_pos + wal.len() as u64;
+        let sends_before = WAL_SENDER_SENDS.get();
+        let capped_sends_before = WAL_SENDER_CAPPED_SENDS.get();
+        let msgs: Vec<_> = sender.into_stream().collect().await;
+
+        let (last, xlog_data) = msgs.split_last().unwrap();
+        assert!(matches!(last, Err(SenderOutcome::ReachedStopPos { at }) if *at == end_pos));
+        let mut pos = start_pos;
+        let mut streamed = Vec::new();
+        for msg in xlog_data {
+            match msg {
+                Ok(WalSenderMsg::XLogData {
+                    wal_start, data, ..
+                }) => {
+                    assert_eq!(*wal_start, pos);
+                    pos += data.len() as u64;
+                    streamed.extend_from_slice(data);
+                }
+                _ => panic!("unexpected message {msg:?}"),
+            }
+        }
+        assert_eq!(pos, end_pos);
+        assert_eq!(streamed, wal);
+
+        // All but the last send are capped by MAX_SEND_SIZE. Other tests
+        // might send concurrently, so the counters can only grow more.
+        assert!(WAL_SENDER_SENDS.get() - sends_before >= 4);
+        assert!(WAL_SENDER_CAPPED_SENDS.get() - capped_sends_before >= 3);
+    }
+
+    // Transport keeping everything written to it.
+    #[derive(Default)]
+    struct Loopback {
+        written: BytesMut,
+    }
+
+    #[async_trait::async_trait]
+    impl WalTransport for Loopback {
+        async fn write_message_flush(
+            &mut self,
+            message: &BeMessage<'_>,
+        ) -> Result<(), ConnectionError> {
+            BeMessage::write(&mut self.written, message)?;
+            Ok(())
+        }
+
+        async fn read_message(&mut self) -> Result<Option<FeMessage>, ConnectionError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_transport() {
+        let wal: Vec<u8> = (0..2 * MAX_SEND_SIZE + 100)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let (sender, start_pos) = wal_sender(&wal, true, None).await;
+        let mut transport = Loopback::default();
+        assert!(matches!(
+            sender.run(&mut transport).await,
+            SenderOutcome::ReachedStopPos { .. }
+        ));
+
+        // Parse back CopyData frames with XLogData.
+        let mut buf = transport.written.freeze();
+        let mut pos = start_pos;
+        let mut streamed = Vec::new();
+        while buf.has_remaining() {
+            assert_eq!(buf.get_u8(), b'd');
+            let len = buf.get_u32() as usize - 4;
+            let mut body = buf.split_to(len);
+            assert_eq!(body.get_u8(), b'w');
+            assert_eq!(Lsn(body.get_u64()), pos);
+            let _wal_end = body.get_u64();
+            let _timestamp = body.get_i64();
+            pos += body.len() as u64;
+            streamed.extend_from_slice(&body);
+        }
+        assert_eq!(pos, start_pos + wal.len() as u64);
+        assert_eq!(streamed, wal);
+    }
+
+    // Receive messages until ErrorResponse, returning its body.
+    async fn recv_error(peer: &mut MockPeer) -> String {
+        loop {
+            match peer.recv().await {
+                Some((b'E', body)) => return String::from_utf8_lossy(&body).into_owned(),
+                Some(_) => continue,
+                None => panic!("connection closed without ErrorResponse"),
+            }
+        }
+    }
+
+    // test that terminate_replicas stops a live replication connection
+    #[tokio::test]
+    async fn test_terminate_replicas() {
+        let (conf, tli, start_pos) = create_timeline_with_wal(&[]).await;
+        let (mut pgb, mut peer) = mock_connection();
+        let mut handler = SafekeeperPostgresHandler::new(conf, 1, None);
+        handler.ttid = tli.ttid;
+        let walsender = tokio::spawn(async move {
+            handler
+                .handle_start_replication(&mut pgb, start_pos)
+                .await
+                .unwrap();
+        });
+
+        // after CopyBothResponse the walsender is registered and waits for WAL
+        assert_eq!(peer.recv().await.unwrap().0, b'W');
+        assert_eq!(tli.get_walsenders().get_all().len(), 1);
+        tli.terminate_replicas();
+        timeout(Duration::from_secs(10), walsender)
+            .await
+            .expect("walsender didn't terminate")
+            .unwrap();
+        let error = recv_error(&mut peer).await;
+        assert!(error.contains("walsender terminated by request"), "{error}");
+        assert!(tli.get_walsenders().get_all().is_empty());
+    }
+
+    // test that second concurrent recovery stream is rejected, while the
+    // first one is still running, and allowed once it is done
+    #[tokio::test]
+    async fn test_concurrent_recovery_sessions() {
+        let wal: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let (conf, tli, start_pos) = create_timeline_with_wal(&wal).await;
+        let start_recovery = |mut pgb: PostgresBackend<DuplexStream>| {
+            let mut handler = SafekeeperPostgresHandler::new(conf.clone(), 1, None);
+            handler.ttid = tli.ttid;
+            handler.appname = Some("wal_proposer_recovery".to_string());
+            tokio::spawn(async move {
+                handler
+                    .handle_start_replication(&mut pgb, start_pos)
+                    .await
+                    .unwrap();
+            })
+        };
+
+        // hold the first stream in the middle of recovery
+        let quiesce_guard = tli.quiesce_replication();
+        let (pgb1, mut peer1) = mock_connection();
+        let first = start_recovery(pgb1);
+        assert_eq!(peer1.recv().await.unwrap().0, b'W'); // CopyBothResponse
+
+        let (pgb2, mut peer2) = mock_connection();
+        start_recovery(pgb2).await.unwrap();
+        let error = recv_error(&mut peer2).await;
+        assert!(
+            error.contains("walproposer recovery stream is already running"),
+            "{error}"
+        );
+
+        drop(quiesce_guard);
+        timeout(Duration::from_secs(10), first)
+            .await
+            .expect("recovery didn't finish")
+            .unwrap();
+        let error = recv_error(&mut peer1).await;
+        assert!(error.contains("recovery finished"), "{error}");
+
+        let (pgb3, mut peer3) = mock_connection();
+        start_recovery(pgb3).await.unwrap();
+        let error = recv_error(&mut peer3).await;
+        assert!(error.contains("recovery finished"), "{error}");
+    }
+
+    // Run walproposer recovery session through the whole
+    // handle_start_replication over in-memory connection.
+    #[tokio::test]
+    async fn test_replication_session() {
+        let wal: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+        let (conf, tli, start_pos) = create_timeline_with_wal(&wal).await;
+        let end_pos = start_pos + wal.len() as u64;
+
+        let (mut pgb, mut peer) = mock_connection();
+        let mut handler = SafekeeperPostgresHandler::new(conf, 1, None);
+        handler.ttid = tli.ttid;
+        handler.appname = Some("wal_proposer_recovery".to_string());
+        handler
+            .handle_start_replication(&mut pgb, start_pos)
+            .await
+            .unwrap();
+        drop(pgb);
+
+        assert_eq!(peer.recv().await.unwrap().0, b'W'); // CopyBothResponse
+        let (tag, mut body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'd');
+        assert_eq!(body.get_u8(), b'w');
+        assert_eq!(Lsn(body.get_u64()), start_pos);
+        assert_eq!(Lsn(body.get_u64()), end_pos);
+        let _timestamp = body.get_i64();
+        assert_eq!(body, wal);
+        // end of recovery is reported as ErrorResponse with successful
+        // completion code
+        let (tag, body) = peer.recv().await.unwrap();
+        assert_eq!(tag, b'E');
+        let error = String::from_utf8_lossy(&body);
+        assert!(error.contains("recovery finished"), "{error}");
+        assert!(peer.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_idle_timeout() {
+        let (sender, _) = wal_sender(&[], false, Some(Duration::from_millis(10))).await;
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        match msgs.next().await.unwrap() {
+            Err(SenderOutcome::IdleTimeout { idle, .. }) => {
+                assert!(idle >= Duration::from_millis(10), "{idle:?}")
+            }
+            res => panic!("expected termination, got {res:?}"),
+        }
+        assert!(msgs.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_caught_up() {
+        let (sender, start_pos) = wal_sender(&[], false, None).await;
+        // pageserver without computes is caught up once there is no data
+        sender
+            .ws_guard
+            .walsenders
+            .record_ps_feedback(sender.ws_guard.id, &PageserverFeedback::empty());
+        let mut transport = Loopback::default();
+        assert!(matches!(
+            sender.run(&mut transport).await,
+            SenderOutcome::CaughtUpNoComputes { at } if at == start_pos
+        ));
+    }
+
+    // Transport of a receiver which went away.
+    struct Broken;
+
+    #[async_trait::async_trait]
+    impl WalTransport for Broken {
+        async fn write_message_flush(
+            &mut self,
+            _message: &BeMessage<'_>,
+        ) -> Result<(), ConnectionError> {
+            Err(ConnectionError::Io(std::io::Error::from(
+                std::io::ErrorKind::BrokenPipe,
+            )))
+        }
+
+        async fn read_message(&mut self) -> Result<Option<FeMessage>, ConnectionError> {
+            Ok(None)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_client_gone() {
+        let (sender, _) = wal_sender(&[0u8; 100], true, None).await;
+        let outcome = sender.run(&mut Broken).await;
+        assert!(
+            matches!(outcome, SenderOutcome::ClientGone(_)),
+            "{outcome:?}"
+        );
+        // the connection error is reported as is
+        match outcome.into_stream_end() {
+            CopyStreamHandlerEnd::Disconnected(ConnectionError::Io(e)) => {
+                assert_eq!(e.kind(), std::io::ErrorKind::BrokenPipe)
+            }
+            end => panic!("expected connection error, got {end:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_error() {
+        let (mut sender, _) = wal_sender(&[0u8; 100], true, None).await;
+        // end of available WAL behind the start position is a bug
+        sender.end_pos = Lsn(0);
+        let mut transport = Loopback::default();
+        match sender.run(&mut transport).await {
+            SenderOutcome::Error(end) => assert!(
+                end.to_string().contains("reading wal without waiting"),
+                "{end}"
+            ),
+            outcome => panic!("expected error, got {outcome:?}"),
+        }
+        assert!(transport.written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_keepalive_wal_end() {
+        let wal = vec![0u8; 100];
+        let (sender, start_pos) = wal_sender(&wal, false, None).await;
+        let flush_lsn = start_pos + wal.len() as u64;
+        sender.tli.truncate_wal(flush_lsn).await.unwrap();
+        // Pretend to be a standby so that walsender doesn't stop without
+        // computes.
+        sender
+            .ws_guard
+            .walsenders
+            .record_standby_reply(sender.ws_guard.id, &StandbyReply::empty());
+
+        // commit_lsn is not advanced, so the sender is caught up and sends
+        // keepalive, which must report commit_lsn rather than flush_lsn.
+        let commit_lsn = *sender.commit_lsn_watch_rx.borrow();
+        assert!(commit_lsn < flush_lsn);
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::KeepAlive { wal_end }) => assert_eq!(wal_end, commit_lsn),
+            res => panic!("expected keepalive, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_recovery_keepalive_wal_end() {
+        let wal = vec![0u8; 100];
+        let (sender, start_pos) = wal_sender(&wal, true, None).await;
+        let flush_lsn = start_pos + wal.len() as u64;
+        sender.tli.truncate_wal(flush_lsn).await.unwrap();
+        let _quiesce_guard = sender.tli.quiesce_replication();
+
+        // walproposer in recovery is streamed uncommitted WAL, so keepalive
+        // reports flush_lsn.
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::KeepAlive { wal_end }) => assert_eq!(wal_end, flush_lsn),
+            res => panic!("expected keepalive, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_quiesce() {
+        let wal = vec![0u8; 100];
+        let (sender, start_pos) = wal_sender(&wal, true, None).await;
+        let quiesce_guard = sender.tli.quiesce_replication();
+
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        // nothing is sent while quiesced, but the connection is kept
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::KeepAlive { .. }) => {}
+            res => panic!("expected keepalive, got {res:?}"),
+        }
+        drop(quiesce_guard);
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::XLogData { wal_start, .. }) => assert_eq!(wal_start, start_pos),
+            res => panic!("expected WAL, got {res:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_max_apply_lag() {
+        // Start of WAL in the next XLogData message, None for keepalive.
+        async fn next_wal_start(
+            msgs: &mut (impl Stream<Item = Result<WalSenderMsg, SenderOutcome>> + Unpin),
+        ) -> Option<Lsn> {
+            match msgs.next().await.unwrap() {
+                Ok(WalSenderMsg::XLogData { wal_start, .. }) => Some(wal_start),
+                Ok(WalSenderMsg::KeepAlive { .. }) => None,
+                res => panic!("unexpected result {res:?}"),
+            }
+        }
+
+        let wal = vec![0u8; 3 * MAX_SEND_SIZE];
+        let (mut sender, start_pos) = wal_sender(&wal, true, None).await;
+        sender.max_apply_lag = Some(MAX_SEND_SIZE as u64);
+        let ws_guard = sender.ws_guard.clone();
+        let mut reply = StandbyReply::empty();
+        reply.apply_lsn = start_pos;
+        ws_guard
+            .walsenders
+            .record_standby_reply(ws_guard.id, &reply);
+
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        // Sending goes on until lag exceeds the threshold...
+        assert_eq!(next_wal_start(&mut msgs).await, Some(start_pos));
+        assert_eq!(
+            next_wal_start(&mut msgs).await,
+            Some(start_pos + MAX_SEND_SIZE as u64)
+        );
+        assert_eq!(next_wal_start(&mut msgs).await, None);
+
+        // ...and resumes once receiver catches up.
+        reply.apply_lsn = start_pos + MAX_SEND_SIZE as u64;
+        ws_guard
+            .walsenders
+            .record_standby_reply(ws_guard.id, &reply);
+        assert_eq!(
+            next_wal_start(&mut msgs).await,
+            Some(start_pos + 2 * MAX_SEND_SIZE as u64)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wal_sender_max_apply_lag_pageserver() {
+        let wal = vec![0u8; 2 * MAX_SEND_SIZE];
+        let (mut sender, start_pos) = wal_sender(&wal, true, None).await;
+        sender.max_apply_lag = Some(0);
+        let ws_guard = sender.ws_guard.clone();
+        // pageserver has received everything, but not uploaded it yet
+        let mut feedback = PageserverFeedback::empty();
+        feedback.last_received_lsn = start_pos + wal.len() as u64;
+        feedback.remote_consistent_lsn = start_pos;
+        ws_guard
+            .walsenders
+            .record_ps_feedback(ws_guard.id, &feedback);
+
+        let msgs = sender.into_stream();
+        pin_mut!(msgs);
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::XLogData { wal_start, .. }) => assert_eq!(wal_start, start_pos),
+            res => panic!("expected WAL, got {res:?}"),
+        }
+        // lag is measured against remote_consistent_lsn
+        match msgs.next().await.unwrap() {
+            Ok(WalSenderMsg::KeepAlive { .. }) => {}
+            res => panic!("expected keepalive, got {res:?}"),
+        }
+
+        // sending resumes right on feedback, not after the timeout
+        feedback.remote_consistent_lsn = start_pos + MAX_SEND_SIZE as u64;
+        let next = msgs.next();
+        pin_mut!(next);
+        assert!(futures::poll!(&mut next).is_pending());
+        ws_guard
+            .walsenders
+            .record_ps_feedback(ws_guard.id, &feedback);
+        match timeout(POLL_STATE_TIMEOUT / 2, next)
+            .await
+            .unwrap()
+            .unwrap()
+        {
+            Ok(WalSenderMsg::XLogData { wal_start, .. }) => {
+                assert_eq!(wal_start, start_pos + MAX_SEND_SIZE as u64)
+            }
+            res => panic!("expected WAL, got {res:?}"),
+        }
+    }
+
+    #[test]
+    fn test_commit_lsn_waiters() {
+        let waiters = CommitLsnWaiters::default();
+        let (_guard1, mut woken1) = waiters.register(Lsn(100));
+        let (_guard2, mut woken2) = waiters.register(Lsn(200));
+        let (_guard3, mut woken3) = waiters.register(Lsn(300));
+
+        // Each waiter is woken up only once commit_lsn passes its position.
+        waiters.notify(Lsn(100));
+        assert!(woken1.try_recv().is_err());
+        waiters.notify(Lsn(150));
+        assert!(woken1.try_recv().is_ok());
+        assert!(woken2.try_recv().is_err());
+        assert!(woken3.try_recv().is_err());
+        waiters.notify(Lsn(250));
+        assert!(woken2.try_recv().is_ok());
+        assert!(woken3.try_recv().is_err());
+
+        // Gone waiters are deregistered.
+        let (guard4, _) = waiters.register(Lsn(300));
+        drop(guard4);
+        assert_eq!(waiters.inner.lock().waiters.len(), 1);
+        waiters.notify(Lsn(301));
+        assert!(woken3.try_recv().is_ok());
+        assert!(waiters.inner.lock().waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_lsn() {
+        let wss = WalSenders::new(Lsn(0));
+        let (commit_lsn_tx, commit_lsn_rx) = watch::channel(Lsn(100));
+
+        // Already reached.
+        assert_eq!(
+            wait_for_lsn(&wss, &commit_lsn_rx, Lsn(50)).await.unwrap(),
+            Some(Lsn(100))
+        );
+
+        let wait = wait_for_lsn(&wss, &commit_lsn_rx, Lsn(200));
+        pin_mut!(wait);
+        assert!((&mut wait).now_or_never().is_none());
+        commit_lsn_tx.send(Lsn(150)).unwrap();
+        wss.notify_commit_lsn(Lsn(150));
+        assert!((&mut wait).now_or_never().is_none());
+        commit_lsn_tx.send(Lsn(250)).unwrap();
+        wss.notify_commit_lsn(Lsn(250));
+        assert_eq!(wait.await.unwrap(), Some(Lsn(250)));
+    }
+
+    #[test]
+    fn test_get_replica_snapshots() {
+        let wss = WalSenders::new(Lsn(0));
+        let ps_guard = wss.register(mock_ttid(), mock_addr(), 1, Some("pageserver".to_string()));
+        let standby_guard = wss.register(mock_ttid(), mock_addr(), 2, Some("standby".to_string()));
+
+        let mut feedback = PageserverFeedback::empty();
+        feedback.disk_consistent_lsn = Lsn(0x100);
+        feedback.last_received_lsn = Lsn(0x200);
+        wss.record_ps_feedback(ps_guard.id, &feedback);
+        let mut reply = StandbyReply::empty();
+        reply.write_lsn = Lsn(0x300);
+        reply.flush_lsn = Lsn(0x300);
+        reply.apply_lsn = Lsn(0x250);
+        reply.reply_ts = 42;
+        wss.record_standby_reply(standby_guard.id, &reply);
+        wss.record_hs_feedback(
+            standby_guard.id,
+            &HotStandbyFeedback {
+                ts: 42,
+                xmin: 10,
+                catalog_xmin: 5,
+            },
+        );
+
+        let snapshots = serde_json::to_value(wss.get_replica_snapshots()).unwrap();
+        let expected = serde_json::json!([
+            {
+                "appname": "pageserver",
+                "write_lsn": "0/200",
+                "flush_lsn": "0/100",
+                "apply_lsn": "0/200",
+                "xmin": 0,
+                "catalog_xmin": 0,
+                "last_reply_ts": 0,
+            },
+            {
+                "appname": "standby",
+                "write_lsn": "0/300",
+                "flush_lsn": "0/300",
+                "apply_lsn": "0/250",
+                "xmin": 10,
+                "catalog_xmin": 5,
+                "last_reply_ts": 42,
+            },
+        ]);
+        assert_eq!(snapshots, expected);
+
+        drop(standby_guard);
+        assert_eq!(wss.get_replica_snapshots().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_list_with_replication_state() {
+        let mut timelines = Vec::new();
+        let mut guards = Vec::new();
+        for (i, wal_len) in [100, 200].into_iter().enumerate() {
+            let (_, tli, start_pos) = create_timeline_with_wal(&vec![0; wal_len]).await;
+            let wss = tli.get_walsenders();
+            let guard = wss.register(tli.ttid, mock_addr(), 1, Some(format!("replica {i}")));
+            let mut reply = StandbyReply::empty();
+            reply.write_lsn = start_pos + wal_len as u64;
+            reply.flush_lsn = start_pos + wal_len as u64;
+            reply.apply_lsn = start_pos;
+            wss.record_standby_reply(guard.id, &reply);
+            guards.push(guard);
+            timelines.push((tli.ttid, start_pos, wal_len as u64));
+        }
+
+        // other tests create timelines concurrently, so look only at ours
+        let states = GlobalTimelines::list_with_replication_state().await;
+        for (i, (ttid, start_pos, wal_len)) in timelines.into_iter().enumerate() {
+            let state = states.iter().find(|s| s.ttid == ttid).unwrap();
+            assert_eq!(state.commit_lsn, Lsn(0));
+            assert_eq!(state.flush_lsn, start_pos + wal_len);
+            assert_eq!(state.replicas.len(), 1);
+            let replica = &state.replicas[0];
+            assert_eq!(replica.appname, Some(format!("replica {i}")));
+            assert_eq!(replica.flush_lsn, start_pos + wal_len);
+            assert_eq!(replica.apply_lsn, start_pos);
+        }
+    }
+
+    #[test]
+    fn test_min_replica_lsn() {
+        let wss = WalSenders::new(Lsn(0));
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn::MAX);
+
+        let standby_reply = |flush_lsn, apply_lsn| {
+            let mut reply = StandbyReply::empty();
+            reply.write_lsn = Lsn(300);
+            reply.flush_lsn = Lsn(flush_lsn);
+            reply.apply_lsn = Lsn(apply_lsn);
+            reply
+        };
+        let lagging_apply = wss.register(mock_ttid(), mock_addr(), 1, None);
+        wss.record_standby_reply(lagging_apply.id, &standby_reply(200, 100));
+        let lagging_flush = wss.register(mock_ttid(), mock_addr(), 2, None);
+        wss.record_standby_reply(lagging_flush.id, &standby_reply(150, 150));
+        let pageserver = wss.register(mock_ttid(), mock_addr(), 3, None);
+        let mut feedback = PageserverFeedback::empty();
+        feedback.last_received_lsn = Lsn(300);
+        feedback.disk_consistent_lsn = Lsn(250);
+        wss.record_ps_feedback(pageserver.id, &feedback);
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn(100));
+
+        // receiver without feedback yet holds all WAL
+        let unreported = wss.register(mock_ttid(), mock_addr(), 4, None);
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn::INVALID);
+        drop(unreported);
+
+        drop(lagging_apply);
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn(150));
+    }
+
+    // test that receivers which stopped reporting their position, or never
+    // did, stop holding WAL after the timeout
+    #[test]
+    fn test_min_replica_lsn_timeout() {
+        let feedback_timeout = Duration::from_millis(150);
+        let wss = WalSenders::new(Lsn(0));
+        let mut reply = StandbyReply::empty();
+        reply.flush_lsn = Lsn(100);
+        reply.apply_lsn = Lsn(100);
+        let silent = wss.register(mock_ttid(), mock_addr(), 1, None);
+        wss.record_standby_reply(silent.id, &reply);
+        let _unreported = wss.register(mock_ttid(), mock_addr(), 2, None);
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn::INVALID);
+
+        std::thread::sleep(feedback_timeout * 2);
+        let active = wss.register(mock_ttid(), mock_addr(), 3, None);
+        reply.flush_lsn = Lsn(200);
+        reply.apply_lsn = Lsn(200);
+        wss.record_standby_reply(active.id, &reply);
+        assert_eq!(wss.get_min_replica_lsn(feedback_timeout), Lsn(200));
+        assert_eq!(wss.get_min_replica_lsn(Duration::MAX), Lsn::INVALID);
+
+        // reporting again makes the receiver hold WAL again
+        reply.flush_lsn = Lsn(150);
+        reply.apply_lsn = Lsn(150);
+        wss.record_standby_reply(silent.id, &reply);
+        assert_eq!(wss.get_min_replica_lsn(feedback_timeout), Lsn(150));
+        drop(active);
+        assert_eq!(wss.get_min_replica_lsn(feedback_timeout), Lsn(150));
+    }
+
+    // test that a replica which stopped reporting its position holds WAL
+    // removal only until the feedback timeout, and not at all with WAL backup
+    #[tokio::test]
+    async fn test_remove_old_wal_silent_replica() {
+        let feedback_timeout = Duration::from_millis(500);
+        for wal_backup_enabled in [false, true] {
+            // Segment 1 is the .partial one written by the helper, 2 is full.
+            // Everything below segment 3 is otherwise safe to remove.
+            let (conf, tli, start_pos) = create_timeline_with_wal(&[]).await;
+            let segment_path =
+                conf.timeline_dir(&tli.ttid)
+                    .join(XLogFileName(PG_TLI, 2, WAL_SEG_SIZE));
+            std::fs::write(&segment_path, b"").unwrap();
+            {
+                let horizon = Lsn(3 * WAL_SEG_SIZE as u64);
+                let mut shared_state = tli.write_shared_state().await;
+                let mut state = (*shared_state.sk.state).clone();
+                state.remote_consistent_lsn = horizon;
+                state.peer_horizon_lsn = horizon;
+                state.backup_lsn = horizon;
+                shared_state.sk.state.persist(&state).await.unwrap();
+            }
+
+            let walsenders = tli.get_walsenders();
+            let silent = walsenders.register(mock_ttid(), mock_addr(), 1, None);
+            let mut reply = StandbyReply::empty();
+            reply.flush_lsn = start_pos;
+            reply.apply_lsn = start_pos;
+            walsenders.record_standby_reply(silent.id, &reply);
+
+            tli.remove_old_wal(wal_backup_enabled, feedback_timeout)
+                .await
+                .unwrap();
+            assert_eq!(segment_path.exists(), !wal_backup_enabled);
+
+            tokio::time::sleep(feedback_timeout * 2).await;
+            tli.remove_old_wal(wal_backup_enabled, feedback_timeout)
+                .await
+                .unwrap();
+            assert!(!segment_path.exists());
+        }
+    }
+
+    // test that changes of aggregated feedback are published, including on
+    // walsender disconnect
+    #[test]
+    fn test_feedbacks_watch() {
+        let wss = WalSenders::new(Lsn(0));
+        let mut rx = wss.subscribe_feedbacks();
+        let ps_guard = wss.register(mock_ttid(), mock_addr(), 1, None);
+        let standby_guard = wss.register(mock_ttid(), mock_addr(), 2, None);
+
+        let mut feedback = PageserverFeedback::empty();
+        feedback.last_received_lsn = Lsn(42);
+        wss.record_ps_feedback(ps_guard.id, &feedback);
+        assert!(rx.has_changed().unwrap());
+        assert_eq!(rx.borrow_and_update().0.last_received_lsn, Lsn(42));
+
+        // the same feedback once again doesn't wake subscribers
+        wss.record_ps_feedback(ps_guard.id, &feedback);
+        assert!(!rx.has_changed().unwrap());
+
+        let hs_feedback = HotStandbyFeedback {
+            ts: 1,
+            xmin: 10,
+            catalog_xmin: 0,
+        };
+        wss.record_hs_feedback(standby_guard.id, &hs_feedback);
+        assert_eq!(rx.borrow_and_update().1.xmin, 10);
+
+        // aggregates are recomputed without the gone walsender
+        drop(ps_guard);
+        assert!(rx.has_changed().unwrap());
+        let (ps_feedback, hs) = *rx.borrow_and_update();
+        assert_eq!(ps_feedback.last_received_lsn, Lsn::INVALID);
+        assert_eq!(hs.xmin, 10);
+
+        drop(standby_guard);
+        assert!(rx.has_changed().unwrap());
+        let (ps_feedback, hs) = *rx.borrow_and_update();
+        assert_eq!(ps_feedback.last_received_lsn, Lsn::INVALID);
+        assert_eq!(hs.xmin, INVALID_FULL_TRANSACTION_ID);
+    }
+
+    #[test]
+    fn test_single_walproposer_recovery() {
+        let wss = WalSenders::new(Lsn(0));
+        let guard = wss.start_walproposer_recovery().unwrap();
+        let err = wss.start_walproposer_recovery().err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "walproposer recovery stream is already running on this timeline"
+        );
+
+        // once the first stream is done, recovery is allowed again
+        drop(guard);
+        wss.start_walproposer_recovery().unwrap();
+    }
 }
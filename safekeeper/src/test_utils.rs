@@ -0,0 +1,119 @@
+//! Helpers to drive connection handlers in unit tests without sockets.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use once_cell::sync::OnceCell;
+use postgres_backend::{AuthType, PostgresBackend};
+use postgres_ffi::{encode_logical_message, XLogFileName, PG_TLI};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use utils::id::TenantTimelineId;
+use utils::lsn::Lsn;
+
+use crate::safekeeper::ServerInfo;
+use crate::timeline::Timeline;
+use crate::{GlobalTimelines, SafeKeeperConf};
+
+/// Size of in-memory pipe buffer in each direction.
+const MOCK_PIPE_SIZE: usize = 1024 * 1024;
+
+/// Create PostgresBackend over in-memory pipe. Returned peer is the other
+/// end of it, speaking raw protocol messages, as compute or pageserver would.
+pub fn mock_connection() -> (PostgresBackend<DuplexStream>, MockPeer) {
+    let (server, client) = tokio::io::duplex(MOCK_PIPE_SIZE);
+    let pgb = PostgresBackend::new_from_io(
+        server,
+        "127.0.0.1:5432".parse().unwrap(),
+        AuthType::Trust,
+        None,
+    )
+    .unwrap();
+    (pgb, MockPeer { stream: client })
+}
+
+/// Frame message the way client sends it.
+pub fn raw_message(tag: u8, body: &[u8]) -> Vec<u8> {
+    let mut msg = vec![tag];
+    msg.extend_from_slice(&(body.len() as u32 + 4).to_be_bytes());
+    msg.extend_from_slice(body);
+    msg
+}
+
+/// Client end of mock connection.
+pub struct MockPeer {
+    stream: DuplexStream,
+}
+
+impl MockPeer {
+    /// Send raw bytes, which are expected to be frontend messages (or their
+    /// malformed versions).
+    pub async fn send_raw(&mut self, data: &[u8]) {
+        self.stream.write_all(data).await.unwrap();
+    }
+
+    /// Receive the next backend message as tag and body, None if the backend
+    /// side is closed.
+    pub async fn recv(&mut self) -> Option<(u8, Bytes)> {
+        let tag = match self.stream.read_u8().await {
+            Ok(tag) => tag,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => panic!("failed to read from mock connection: {e}"),
+        };
+        let len = self.stream.read_u32().await.unwrap() as usize;
+        let mut body = vec![0; len - 4];
+        self.stream.read_exact(&mut body).await.unwrap();
+        Some((tag, Bytes::from(body)))
+    }
+}
+
+/// Initialize GlobalTimelines in a temporary directory, once per test
+/// process, and return its config.
+pub fn init_global_timelines() -> SafeKeeperConf {
+    static CONF: OnceCell<SafeKeeperConf> = OnceCell::new();
+    CONF.get_or_init(|| {
+        let conf = SafeKeeperConf {
+            workdir: tempfile::tempdir().unwrap().into_path(),
+            ..SafeKeeperConf::dummy()
+        };
+        let (wal_backup_launcher_tx, wal_backup_launcher_rx) = tokio::sync::mpsc::channel(100);
+        // There is no launcher in tests, but timeline creation notifies it,
+        // so the channel must stay open.
+        std::mem::forget(wal_backup_launcher_rx);
+        GlobalTimelines::init(conf.clone(), wal_backup_launcher_tx).unwrap();
+        conf
+    })
+    .clone()
+}
+
+/// WAL segment size of timelines and storages created in tests.
+pub const WAL_SEG_SIZE: usize = 16 * 1024 * 1024;
+
+/// Logical message WAL record, padded to 8 bytes as records are aligned in
+/// WAL, so that the next one can follow right after it.
+pub fn logical_message(prefix: &str, message: &str) -> Vec<u8> {
+    let mut wal_data = encode_logical_message(prefix, message);
+    wal_data.resize((wal_data.len() + 7) & !7, 0);
+    wal_data
+}
+
+/// Create timeline in GlobalTimelines with the given WAL written (but not
+/// committed) at the start of the second segment, which is returned.
+pub async fn create_timeline_with_wal(wal: &[u8]) -> (SafeKeeperConf, Arc<Timeline>, Lsn) {
+    let conf = init_global_timelines();
+    let ttid = TenantTimelineId::generate();
+    let server_info = ServerInfo {
+        pg_version: 150000,
+        system_id: 0,
+        wal_seg_size: WAL_SEG_SIZE as u32,
+    };
+    let start_pos = Lsn(WAL_SEG_SIZE as u64);
+    let tli = GlobalTimelines::create(ttid, server_info, Lsn(0), start_pos)
+        .await
+        .unwrap();
+    let segment_name = XLogFileName(PG_TLI, 1, WAL_SEG_SIZE) + ".partial";
+    std::fs::write(conf.timeline_dir(&ttid).join(segment_name), wal).unwrap();
+    tli.truncate_wal(start_pos + wal.len() as u64)
+        .await
+        .unwrap();
+    (conf, tli, start_pos)
+}
@@ -3,11 +3,14 @@
 
 use anyhow::{anyhow, bail, Result};
 use postgres_ffi::XLogSegNo;
+use serde::Serialize;
+use serde_with::{serde_as, DisplayFromStr};
 use tokio::fs;
 
-use std::cmp::max;
+use std::cmp::{max, min};
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::{Mutex, MutexGuard};
 use tokio::{
     sync::{mpsc::Sender, watch},
@@ -27,7 +30,7 @@ use crate::safekeeper::{
     AcceptorProposerMessage, ProposerAcceptorMessage, SafeKeeper, SafeKeeperState,
     SafekeeperMemState, ServerInfo, Term,
 };
-use crate::send_wal::WalSenders;
+use crate::send_wal::{ReplicaSnapshot, WalSenders};
 use crate::{control_file, safekeeper::UNKNOWN_SERVER_VERSION};
 
 use crate::metrics::FullTimelineInfo;
@@ -273,6 +276,29 @@ impl From<TimelineError> for ApiError {
     }
 }
 
+/// Replication state of a timeline: how much WAL we have and where its
+/// receivers are.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct TimelineReplicationState {
+    #[serde_as(as = "DisplayFromStr")]
+    pub ttid: TenantTimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    pub commit_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub flush_lsn: Lsn,
+    pub replicas: Vec<ReplicaSnapshot>,
+}
+
+/// Feedback of all replicas of a timeline.
+#[serde_as]
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicationSnapshot {
+    #[serde_as(as = "DisplayFromStr")]
+    pub ttid: TenantTimelineId,
+    pub replicas: Vec<ReplicaSnapshot>,
+}
+
 /// Timeline struct manages lifecycle (creation, deletion, restore) of a safekeeper timeline.
 /// It also holds SharedState and provides mutually exclusive access to it.
 pub struct Timeline {
@@ -302,6 +328,21 @@ pub struct Timeline {
 
     /// Directory where timeline state is stored.
     pub timeline_dir: PathBuf,
+
+    /// Number of alive QuiesceGuards; replication is paused while it is not
+    /// zero.
+    quiesce_tx: Arc<watch::Sender<usize>>,
+}
+
+/// Scope guard of paused replication, see Timeline::quiesce_replication.
+pub struct QuiesceGuard {
+    quiesce_tx: Arc<watch::Sender<usize>>,
+}
+
+impl Drop for QuiesceGuard {
+    fn drop(&mut self) {
+        self.quiesce_tx.send_modify(|n| *n -= 1);
+    }
 }
 
 impl Timeline {
@@ -329,6 +370,7 @@ impl Timeline {
             cancellation_rx,
             cancellation_tx,
             timeline_dir: conf.timeline_dir(&ttid),
+            quiesce_tx: Arc::new(watch::channel(0).0),
         })
     }
 
@@ -355,6 +397,7 @@ impl Timeline {
             cancellation_rx,
             cancellation_tx,
             timeline_dir: conf.timeline_dir(&ttid),
+            quiesce_tx: Arc::new(watch::channel(0).0),
         })
     }
 
@@ -422,11 +465,19 @@ impl Timeline {
     fn cancel(&self, shared_state: &mut MutexGuard<'_, SharedState>) {
         info!("timeline {} is cancelled", self.ttid);
         let _ = self.cancellation_tx.send(true);
+        // Streaming connections hold the timeline; make them go away.
+        self.terminate_replicas();
         // Close associated FDs. Nobody will be able to touch timeline data once
         // it is cancelled, so WAL storage won't be opened again.
         shared_state.sk.wal_store.close();
     }
 
+    /// Ask all active START_REPLICATION connections of the timeline to
+    /// stop streaming and exit.
+    pub fn terminate_replicas(&self) {
+        self.walsenders.terminate_all();
+    }
+
     /// Returns if timeline is cancelled.
     pub fn is_cancelled(&self) -> bool {
         *self.cancellation_rx.borrow()
@@ -506,6 +557,14 @@ impl Timeline {
         self.write_shared_state().await.wal_backup_attend()
     }
 
+    /// Truncate WAL at the given LSN bypassing the protocol, for tests
+    /// needing flush_lsn at some position.
+    #[cfg(test)]
+    pub async fn truncate_wal(&self, end_pos: Lsn) -> Result<()> {
+        let mut shared_state = self.write_shared_state().await;
+        shared_state.sk.wal_store.truncate_wal(end_pos).await
+    }
+
     /// Returns commit_lsn watch channel.
     pub fn get_commit_lsn_watch_rx(&self) -> watch::Receiver<Lsn> {
         self.commit_lsn_watch_rx.clone()
@@ -537,6 +596,7 @@ impl Timeline {
             commit_lsn = shared_state.sk.inmem.commit_lsn;
         }
         self.commit_lsn_watch_tx.send(commit_lsn)?;
+        self.walsenders.notify_commit_lsn(commit_lsn);
         Ok(rmsg)
     }
 
@@ -607,6 +667,7 @@ impl Timeline {
             commit_lsn = shared_state.sk.inmem.commit_lsn;
         }
         self.commit_lsn_watch_tx.send(commit_lsn)?;
+        self.walsenders.notify_commit_lsn(commit_lsn);
         // Wake up wal backup launcher, if it is time to stop the offloading.
         if is_wal_backup_action_pending {
             self.wal_backup_launcher_tx.send(self.ttid).await?;
@@ -634,6 +695,56 @@ impl Timeline {
         &self.walsenders
     }
 
+    /// Returns commit and flush LSNs along with feedback of all active
+    /// replicas.
+    pub async fn get_replication_state(&self) -> TimelineReplicationState {
+        let (commit_lsn, flush_lsn) = {
+            let state = self.write_shared_state().await;
+            (state.sk.inmem.commit_lsn, state.sk.wal_store.flush_lsn())
+        };
+        TimelineReplicationState {
+            ttid: self.ttid,
+            commit_lsn,
+            flush_lsn,
+            replicas: self.walsenders.get_replica_snapshots(),
+        }
+    }
+
+    /// Returns feedback of all active replicas.
+    pub fn replication_snapshot(&self) -> ReplicationSnapshot {
+        ReplicationSnapshot {
+            ttid: self.ttid,
+            replicas: self.walsenders.get_replica_snapshots(),
+        }
+    }
+
+    /// Returns position below which WAL is not needed by any of the connected
+    /// replicas, Lsn::MAX if there are none. Replicas which haven't reported
+    /// their position yet hold it at INVALID. Replicas which didn't report
+    /// their position during `feedback_timeout` are not taken into account.
+    pub fn replication_gc_horizon(&self, feedback_timeout: Duration) -> Lsn {
+        self.walsenders.get_min_replica_lsn(feedback_timeout)
+    }
+
+    /// Pause replication of the timeline until the returned guard is dropped,
+    /// e.g. to take a consistent checkpoint: walsenders stop sending WAL,
+    /// keeping connections alive with keepalives, and WalAcceptors stop
+    /// accepting it after flushing what was already received, keepalives
+    /// going on too. If guards overlap, replication resumes once all of them
+    /// are dropped.
+    pub fn quiesce_replication(&self) -> QuiesceGuard {
+        self.quiesce_tx.send_modify(|n| *n += 1);
+        QuiesceGuard {
+            quiesce_tx: self.quiesce_tx.clone(),
+        }
+    }
+
+    /// Subscribe to the number of alive QuiesceGuards, replication must be
+    /// paused while it is not zero.
+    pub fn subscribe_quiesce(&self) -> watch::Receiver<usize> {
+        self.quiesce_tx.subscribe()
+    }
+
     /// Returns flush_lsn.
     pub async fn get_flush_lsn(&self) -> Lsn {
         self.write_shared_state().await.sk.wal_store.flush_lsn()
@@ -641,7 +752,14 @@ impl Timeline {
 
     /// Delete WAL segments from disk that are no longer needed. This is determined
     /// based on pageserver's remote_consistent_lsn and local backup_lsn/peer_lsn.
-    pub async fn remove_old_wal(&self, wal_backup_enabled: bool) -> Result<()> {
+    /// Without WAL backup, WAL still needed by connected replicas is also kept,
+    /// unless they didn't report their position during `replica_feedback_timeout`.
+    /// With it, walsenders read removed WAL from remote storage.
+    pub async fn remove_old_wal(
+        &self,
+        wal_backup_enabled: bool,
+        replica_feedback_timeout: Duration,
+    ) -> Result<()> {
         if self.is_cancelled() {
             bail!(TimelineError::Cancelled(self.ttid));
         }
@@ -649,7 +767,16 @@ impl Timeline {
         let horizon_segno: XLogSegNo;
         let remover = {
             let shared_state = self.write_shared_state().await;
-            horizon_segno = shared_state.sk.get_horizon_segno(wal_backup_enabled);
+            let wal_seg_size = shared_state.sk.state.server.wal_seg_size as usize;
+            horizon_segno = if wal_backup_enabled {
+                shared_state.sk.get_horizon_segno(wal_backup_enabled)
+            } else {
+                min(
+                    shared_state.sk.get_horizon_segno(wal_backup_enabled),
+                    self.replication_gc_horizon(replica_feedback_timeout)
+                        .segment_number(wal_seg_size),
+                )
+            };
             if horizon_segno <= 1 || horizon_segno <= shared_state.last_removed_segno {
                 return Ok(()); // nothing to do
             }
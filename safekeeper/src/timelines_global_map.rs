@@ -3,7 +3,7 @@
 //! all from the disk on startup and keeping them in memory.
 
 use crate::safekeeper::ServerInfo;
-use crate::timeline::{Timeline, TimelineError};
+use crate::timeline::{Timeline, TimelineError, TimelineReplicationState};
 use crate::SafeKeeperConf;
 use anyhow::{bail, Context, Result};
 use once_cell::sync::Lazy;
@@ -289,6 +289,16 @@ impl GlobalTimelines {
             .collect()
     }
 
+    /// Returns replication state of all timelines. Timelines are locked one by
+    /// one, so the result is not an atomic snapshot across timelines.
+    pub async fn list_with_replication_state() -> Vec<TimelineReplicationState> {
+        let mut res = Vec::new();
+        for tli in Self::get_all() {
+            res.push(tli.get_replication_state().await);
+        }
+        res
+    }
+
     /// Returns all timelines belonging to a given tenant. Used for deleting all timelines of a tenant,
     /// and that's why it can return cancelled timelines, to retry deleting them.
     fn get_all_for_tenant(tenant_id: TenantId) -> Vec<Arc<Timeline>> {
@@ -8,7 +8,7 @@
 //! Note that last file has `.partial` suffix, that's different from postgres.
 
 use anyhow::{bail, Context, Result};
-use bytes::Bytes;
+use bytes::{BufMut, Bytes, BytesMut};
 use futures::future::BoxFuture;
 use postgres_ffi::v14::xlog_utils::{IsPartialXLogFileName, IsXLogFileName, XLogFromFileName};
 use postgres_ffi::{XLogSegNo, PG_TLI};
@@ -17,8 +17,10 @@ use std::cmp::{max, min};
 use std::io::{self, SeekFrom};
 use std::path::{Path, PathBuf};
 use std::pin::Pin;
+use std::str::FromStr;
+use std::task::{Context as TaskContext, Poll};
 use tokio::fs::{self, remove_file, File, OpenOptions};
-use tokio::io::{AsyncRead, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncWriteExt, ReadBuf};
 use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tracing::*;
 
@@ -60,6 +62,35 @@ pub trait Storage {
     fn get_metrics(&self) -> WalStorageMetrics;
 }
 
+/// How WAL is made durable on flush.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalSyncMethod {
+    /// fdatasync the segment on flush. Syncs data and only metadata needed
+    /// to read it back (file size); segments are preallocated, so this is
+    /// enough for WAL.
+    Fdatasync,
+    /// fsync the segment on flush. Like fdatasync, but also syncs metadata
+    /// not needed for durability, e.g. mtime, so usually slower.
+    Fsync,
+    /// Open segments with O_DSYNC, making each write durable before it
+    /// returns, so flush has nothing to do. Avoids separate syscall on
+    /// flush, but writes which are not followed by flush are synced too.
+    OpenDsync,
+}
+
+impl FromStr for WalSyncMethod {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fdatasync" => Ok(WalSyncMethod::Fdatasync),
+            "fsync" => Ok(WalSyncMethod::Fsync),
+            "open_dsync" => Ok(WalSyncMethod::OpenDsync),
+            _ => bail!("unknown WAL sync method {s:?}, expected fdatasync, fsync or open_dsync"),
+        }
+    }
+}
+
 /// PhysicalStorage is a storage that stores WAL on disk. Writes are separated from flushes
 /// for better performance. Storage is initialized in the constructor.
 ///
@@ -187,11 +218,17 @@ impl PhysicalStorage {
         )
     }
 
-    /// Call fdatasync if config requires so.
+    /// Make written WAL durable with configured sync method, if config
+    /// requires so.
     async fn fdatasync_file(&mut self, file: &mut File) -> Result<()> {
         if !self.conf.no_sync {
-            self.metrics
-                .observe_flush_seconds(time_io_closure(file.sync_data()).await?);
+            let flush_seconds = match self.conf.wal_sync_method {
+                WalSyncMethod::Fdatasync => time_io_closure(file.sync_data()).await?,
+                WalSyncMethod::Fsync => time_io_closure(file.sync_all()).await?,
+                // writes are durable already, and timed as flushes
+                WalSyncMethod::OpenDsync => return Ok(()),
+            };
+            self.metrics.observe_flush_seconds(flush_seconds);
         }
         Ok(())
     }
@@ -212,13 +249,9 @@ impl PhysicalStorage {
             wal_file_paths(&self.timeline_dir, segno, self.wal_seg_size)?;
 
         // Try to open already completed segment
-        if let Ok(file) = OpenOptions::new().write(true).open(&wal_file_path).await {
+        if let Ok(file) = self.write_options().open(&wal_file_path).await {
             Ok((file, false))
-        } else if let Ok(file) = OpenOptions::new()
-            .write(true)
-            .open(&wal_file_partial_path)
-            .await
-        {
+        } else if let Ok(file) = self.write_options().open(&wal_file_partial_path).await {
             // Try to open existing partial file
             Ok((file, true))
         } else {
@@ -232,10 +265,30 @@ impl PhysicalStorage {
 
             write_zeroes(&mut file, self.wal_seg_size).await?;
             self.fsync_file(&mut file).await?;
+            if self.is_dsync() {
+                // zeroes are written in bulk without O_DSYNC, now reopen
+                // with it for writing WAL
+                file = self.write_options().open(&wal_file_partial_path).await?;
+            }
             Ok((file, true))
         }
     }
 
+    /// Whether segments are opened with O_DSYNC.
+    fn is_dsync(&self) -> bool {
+        !self.conf.no_sync && self.conf.wal_sync_method == WalSyncMethod::OpenDsync
+    }
+
+    /// Options to open existing segment for writing.
+    fn write_options(&self) -> OpenOptions {
+        let mut options = OpenOptions::new();
+        options.write(true);
+        if self.is_dsync() {
+            options.custom_flags(nix::libc::O_DSYNC);
+        }
+        options
+    }
+
     /// Write WAL bytes, which are known to be located in a single WAL segment.
     async fn write_in_segment(&mut self, segno: u64, xlogoff: usize, buf: &[u8]) -> Result<()> {
         let mut file = if let Some(file) = self.file.take() {
@@ -335,6 +388,10 @@ impl Storage for PhysicalStorage {
         let write_seconds = time_io_closure(self.write_exact(startpos, buf)).await?;
         // WAL is written, updating write metrics
         self.metrics.observe_write_seconds(write_seconds);
+        if self.is_dsync() {
+            // with O_DSYNC the write makes WAL durable, so it is the flush
+            self.metrics.observe_flush_seconds(write_seconds);
+        }
         self.metrics.observe_write_bytes(buf.len());
 
         // figure out last record's end lsn for reporting (if we got the
@@ -509,6 +566,9 @@ pub struct WalReader {
 
     // S3 will be used to read WAL if LSN is not available locally
     enable_remote_read: bool,
+    // Drop pages of completed local WAL segments from page cache once they
+    // are read
+    drop_cache: bool,
 
     // We don't have WAL locally if LSN is less than local_start_lsn
     local_start_lsn: Lsn,
@@ -528,6 +588,7 @@ impl WalReader {
         state: &SafeKeeperState,
         start_pos: Lsn,
         enable_remote_read: bool,
+        drop_cache: bool,
     ) -> Result<Self> {
         if state.server.wal_seg_size == 0 || state.local_start_lsn == Lsn(0) {
             bail!("state uninitialized, no data to read");
@@ -557,6 +618,7 @@ impl WalReader {
             pos: start_pos,
             wal_segment: None,
             enable_remote_read,
+            drop_cache,
             local_start_lsn: state.local_start_lsn,
             timeline_start_lsn: state.timeline_start_lsn,
             pg_version: state.server.pg_version / 10000,
@@ -565,7 +627,10 @@ impl WalReader {
         })
     }
 
-    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+    /// Read up to `max_len` bytes of WAL at the current position, appending
+    /// them to `buf`. Bytes are read directly into the spare capacity of
+    /// `buf`, so callers should reserve it in advance to avoid reallocation.
+    pub async fn read(&mut self, buf: &mut BytesMut, max_len: usize) -> Result<usize> {
         // If this timeline is new, we may not have a full segment yet, so
         // we pad the first bytes of the timeline's first WAL segment with 0s
         if self.pos < self.timeline_start_lsn {
@@ -602,8 +667,8 @@ impl WalReader {
             debug_assert!(seg_bytes.len() > tl_start_seg_offset);
 
             // Copy as many bytes as possible into the buffer
-            let len = (tl_start_seg_offset - pos_seg_offset).min(buf.len());
-            buf[0..len].copy_from_slice(&seg_bytes[pos_seg_offset..pos_seg_offset + len]);
+            let len = (tl_start_seg_offset - pos_seg_offset).min(max_len);
+            buf.extend_from_slice(&seg_bytes[pos_seg_offset..pos_seg_offset + len]);
 
             self.pos += len as u64;
 
@@ -625,11 +690,19 @@ impl WalReader {
         // How much to read and send in message? We cannot cross the WAL file
         // boundary, and we don't want send more than provided buffer.
         let xlogoff = self.pos.segment_offset(self.wal_seg_size);
-        let send_size = min(buf.len(), self.wal_seg_size - xlogoff);
+        let send_size = min(max_len, self.wal_seg_size - xlogoff);
 
         // Read some data from the file.
-        let buf = &mut buf[0..send_size];
-        let send_size = wal_segment.read_exact(buf).await?;
+        let mut remaining = send_size;
+        while remaining > 0 {
+            let n = wal_segment
+                .read_buf(&mut (&mut *buf).limit(remaining))
+                .await?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            remaining -= n;
+        }
         self.pos += send_size as u64;
 
         // Decide whether to reuse this file. If we don't set wal_segment here
@@ -652,8 +725,14 @@ impl WalReader {
         if self.pos >= self.local_start_lsn {
             let res = Self::open_wal_file(&wal_file_path).await;
             match res {
-                Ok(mut file) => {
+                Ok((mut file, is_partial)) => {
                     file.seek(SeekFrom::Start(xlogoff as u64)).await?;
+                    // Pages of the segment being written are still hot: they
+                    // are being appended to and other walsenders are about
+                    // to read them.
+                    if self.drop_cache && !is_partial {
+                        return Ok(Box::pin(DropCacheFile::new(file, xlogoff as u64)));
+                    }
                     return Ok(Box::pin(file));
                 }
                 Err(e) => {
@@ -690,18 +769,20 @@ impl WalReader {
         bail!("WAL segment is not found")
     }
 
-    /// Helper function for opening a wal file.
-    async fn open_wal_file(wal_file_path: &Path) -> Result<tokio::fs::File> {
+    /// Helper function for opening a wal file. Also returns whether it is the
+    /// .partial one.
+    async fn open_wal_file(wal_file_path: &Path) -> Result<(tokio::fs::File, bool)> {
         // First try to open the .partial file.
         let mut partial_path = wal_file_path.to_owned();
         partial_path.set_extension("partial");
         if let Ok(opened_file) = tokio::fs::File::open(&partial_path).await {
-            return Ok(opened_file);
+            return Ok((opened_file, true));
         }
 
         // If that failed, try it without the .partial extension.
         tokio::fs::File::open(&wal_file_path)
             .await
+            .map(|file| (file, false))
             .with_context(|| format!("Failed to open WAL file {:?}", wal_file_path))
             .map_err(|e| {
                 warn!("{}", e);
@@ -710,6 +791,69 @@ impl WalReader {
     }
 }
 
+/// File reader advising the kernel that the file is read sequentially and
+/// that pages already read won't be needed again, so streaming WAL to
+/// replicas doesn't evict hot data from page cache.
+///
+/// The advice is per file, not per reader: pages are dropped even if other
+/// walsenders of the timeline haven't read them yet, so those have to go
+/// to disk. That is the price of not polluting the cache when the timeline
+/// has several receivers which are not in lockstep.
+struct DropCacheFile {
+    file: File,
+    // current offset in the file
+    pos: u64,
+}
+
+impl DropCacheFile {
+    fn new(file: File, pos: u64) -> Self {
+        Self::advise(&file, pos, 0, Advice::Sequential);
+        DropCacheFile { file, pos }
+    }
+
+    // The advice is only a hint, so errors are not interesting.
+    #[cfg(target_os = "linux")]
+    fn advise(file: &File, offset: u64, len: u64, advice: Advice) {
+        use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+        use nix::libc::off_t;
+        use std::os::unix::io::AsRawFd;
+
+        let advice = match advice {
+            Advice::Sequential => PosixFadviseAdvice::POSIX_FADV_SEQUENTIAL,
+            Advice::DontNeed => PosixFadviseAdvice::POSIX_FADV_DONTNEED,
+        };
+        let _ = posix_fadvise(file.as_raw_fd(), offset as off_t, len as off_t, advice);
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn advise(_file: &File, _offset: u64, _len: u64, _advice: Advice) {}
+}
+
+#[derive(Clone, Copy)]
+enum Advice {
+    Sequential,
+    DontNeed,
+}
+
+impl AsyncRead for DropCacheFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled_before = buf.filled().len();
+        let res = Pin::new(&mut self.file).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res {
+            let read = (buf.filled().len() - filled_before) as u64;
+            if read > 0 {
+                Self::advise(&self.file, self.pos, read, Advice::DontNeed);
+                self.pos += read;
+            }
+        }
+        res
+    }
+}
+
 /// Zero block for filling created WAL segments.
 const ZERO_BLOCK: &[u8] = &[0u8; XLOG_BLCKSZ];
 
@@ -735,3 +879,178 @@ fn wal_file_paths(
     let wal_file_partial_path = timeline_dir.join(wal_file_name + ".partial");
     Ok((wal_file_path, wal_file_partial_path))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::safekeeper::ServerInfo;
+    use crate::test_utils::{logical_message, WAL_SEG_SIZE};
+    use crate::SafeKeeperConf;
+
+    // Write a segment filled with a pattern and read it back in chunks,
+    // starting in the middle of the segment.
+    async fn read_wal(drop_cache: bool, partial: bool) {
+        let timeline_dir = tempfile::tempdir().unwrap().into_path();
+        let segment: Vec<u8> = (0..4 * XLOG_BLCKSZ).map(|i| (i % 251) as u8).collect();
+        let (path, partial_path) = wal_file_paths(&timeline_dir, 1, WAL_SEG_SIZE).unwrap();
+        std::fs::write(if partial { partial_path } else { path }, &segment).unwrap();
+
+        let segment_start = Lsn(WAL_SEG_SIZE as u64);
+        let mut state = SafeKeeperState::new(
+            &TenantTimelineId::empty(),
+            ServerInfo {
+                pg_version: 150000,
+                system_id: 0,
+                wal_seg_size: WAL_SEG_SIZE as u32,
+            },
+            vec![],
+            segment_start,
+            segment_start,
+        );
+        state.timeline_start_lsn = segment_start;
+
+        let start_offset = 100;
+        let mut reader = WalReader::new(
+            timeline_dir.clone(),
+            timeline_dir,
+            &state,
+            segment_start + start_offset as u64,
+            false,
+            drop_cache,
+        )
+        .unwrap();
+
+        let mut read = BytesMut::new();
+        while start_offset + read.len() < segment.len() {
+            let len = min(1000, segment.len() - start_offset - read.len());
+            let n = reader.read(&mut read, len).await.unwrap();
+            assert!(n > 0);
+        }
+        assert_eq!(read, &segment[start_offset..]);
+    }
+
+    #[tokio::test]
+    async fn test_wal_reader() {
+        read_wal(false, true).await;
+        read_wal(false, false).await;
+    }
+
+    #[tokio::test]
+    async fn test_wal_reader_drop_cache() {
+        read_wal(true, true).await;
+        read_wal(true, false).await;
+    }
+
+    // Create empty storage in a temporary directory, which is returned too.
+    fn physical_storage(wal_sync_method: WalSyncMethod) -> (PathBuf, PhysicalStorage) {
+        let workdir = tempfile::tempdir().unwrap().into_path();
+        let conf = SafeKeeperConf {
+            workdir: workdir.clone(),
+            wal_sync_method,
+            ..SafeKeeperConf::dummy()
+        };
+        let ttid = TenantTimelineId::generate();
+        let server_info = ServerInfo {
+            pg_version: 150000,
+            system_id: 0,
+            wal_seg_size: WAL_SEG_SIZE as u32,
+        };
+        let state = SafeKeeperState::new(&ttid, server_info, vec![], Lsn(0), Lsn(0));
+        let storage = PhysicalStorage::new(&ttid, workdir.clone(), &conf, &state).unwrap();
+        (workdir, storage)
+    }
+
+    // Write a few records to the storage, flushing after each one. Returns
+    // time spent syncing in writes and in flushes, as recorded in the storage
+    // metrics.
+    async fn write_and_flush(storage: &mut PhysicalStorage, n_records: usize) -> (f64, f64) {
+        let mut lsn = max(storage.flush_lsn(), Lsn(WAL_SEG_SIZE as u64 + 0x100));
+        let (mut write_sync_seconds, mut flush_sync_seconds) = (0.0, 0.0);
+        for i in 0..n_records {
+            let wal_data = logical_message("prefix", &format!("message {i}"));
+            let before_write = storage.get_metrics().flush_wal_seconds();
+            storage.write_wal(lsn, &wal_data).await.unwrap();
+            lsn += wal_data.len() as u64;
+            let before_flush = storage.get_metrics().flush_wal_seconds();
+            storage.flush_wal().await.unwrap();
+            assert_eq!(storage.flush_lsn(), lsn);
+            write_sync_seconds += before_flush - before_write;
+            flush_sync_seconds += storage.get_metrics().flush_wal_seconds() - before_flush;
+        }
+        (write_sync_seconds, flush_sync_seconds)
+    }
+
+    // Flushes dominate commit latency, so time spent in them must be recorded
+    // in the storage metrics (and safekeeper_flush_wal_seconds with them), and
+    // they must be done as the configured sync method requires.
+    #[tokio::test]
+    async fn test_flush_metrics() {
+        for method in [
+            WalSyncMethod::Fdatasync,
+            WalSyncMethod::Fsync,
+            WalSyncMethod::OpenDsync,
+        ] {
+            let (_, mut storage) = physical_storage(method);
+            // creates the segment, syncing zeroes it is filled with
+            write_and_flush(&mut storage, 1).await;
+
+            let started_at = std::time::Instant::now();
+            let (write_sync_seconds, flush_sync_seconds) = write_and_flush(&mut storage, 3).await;
+            let elapsed = started_at.elapsed().as_secs_f64();
+            if method == WalSyncMethod::OpenDsync {
+                // writes are durable by themselves, and timed as flushes
+                assert!(write_sync_seconds > 0.0);
+                assert_eq!(flush_sync_seconds, 0.0);
+            } else {
+                assert_eq!(write_sync_seconds, 0.0, "{method:?}");
+                assert!(flush_sync_seconds > 0.0, "{method:?}");
+            }
+            let sync_seconds = write_sync_seconds + flush_sync_seconds;
+            assert!(
+                sync_seconds <= elapsed,
+                "{method:?}: {sync_seconds} > {elapsed}"
+            );
+        }
+    }
+
+    // Open flags of a segment opened for writing, freshly created or existing.
+    #[cfg(target_os = "linux")]
+    async fn segment_open_flags(wal_sync_method: WalSyncMethod, create: bool) -> i32 {
+        use std::os::unix::io::AsRawFd;
+
+        let (workdir, mut storage) = physical_storage(wal_sync_method);
+        if !create {
+            let (_, partial_path) = wal_file_paths(&workdir, 1, WAL_SEG_SIZE).unwrap();
+            std::fs::write(partial_path, vec![0u8; WAL_SEG_SIZE]).unwrap();
+        }
+
+        let (file, is_partial) = storage.open_or_create(1).await.unwrap();
+        assert!(is_partial);
+        let fdinfo =
+            std::fs::read_to_string(format!("/proc/self/fdinfo/{}", file.as_raw_fd())).unwrap();
+        let flags = fdinfo
+            .lines()
+            .find_map(|l| l.strip_prefix("flags:"))
+            .unwrap()
+            .trim();
+        i32::from_str_radix(flags, 8).unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_wal_sync_method() {
+        for create in [true, false] {
+            let flags = segment_open_flags(WalSyncMethod::OpenDsync, create).await;
+            assert_ne!(flags & nix::libc::O_DSYNC, 0, "create={create}");
+            for method in [WalSyncMethod::Fdatasync, WalSyncMethod::Fsync] {
+                let flags = segment_open_flags(method, create).await;
+                assert_eq!(flags & nix::libc::O_DSYNC, 0, "{method:?} create={create}");
+            }
+        }
+        assert_eq!(
+            "open_dsync".parse::<WalSyncMethod>().unwrap(),
+            WalSyncMethod::OpenDsync
+        );
+        assert!("sync".parse::<WalSyncMethod>().is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Transport over which WAL is streamed.
+
+use postgres_backend::PostgresBackend;
+use pq_proto::framed::ConnectionError;
+use pq_proto::{BeMessage, FeMessage};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Framed messages transport of WAL streaming. Currently that's always
+/// PostgresBackend, but streaming logic doesn't depend on it, so WAL can be
+/// streamed over anything else able to pass protocol messages.
+#[async_trait::async_trait]
+pub trait WalTransport: Send {
+    /// Write message and flush it to the peer.
+    async fn write_message_flush(&mut self, message: &BeMessage<'_>)
+        -> Result<(), ConnectionError>;
+
+    /// Read the next message, or None if the peer cleanly closed the
+    /// transport.
+    async fn read_message(&mut self) -> Result<Option<FeMessage>, ConnectionError>;
+}
+
+#[async_trait::async_trait]
+impl<IO: AsyncRead + AsyncWrite + Unpin + Send> WalTransport for PostgresBackend<IO> {
+    async fn write_message_flush(
+        &mut self,
+        message: &BeMessage<'_>,
+    ) -> Result<(), ConnectionError> {
+        self.write_message(message).await?;
+        Ok(())
+    }
+
+    async fn read_message(&mut self) -> Result<Option<FeMessage>, ConnectionError> {
+        PostgresBackend::read_message(self).await
+    }
+}